@@ -0,0 +1,120 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::transport::Address;
+
+/// The format of the lines written by an [`AccessLog`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// a line similar to the Common Log Format, i.e.
+    /// `peer - - [timestamp] "METHOD resource jsontp/version" status response-bytes request-bytes encoding duration-us`,
+    /// with quotes, backslashes and control characters in the values from the request escaped
+    Common,
+    /// one JSON object per line, with a field for each part of the [`AccessLogEntry`]
+    JsonLines,
+}
+
+/// Everything recorded about a single request in the access log
+#[derive(Debug, Clone)]
+pub struct AccessLogEntry {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub peer: Address,
+    pub jsontp: String,
+    pub method: String,
+    pub resource: String,
+    pub status: u16,
+    /// the encoding of the request body
+    pub encoding: String,
+    pub request_bytes: usize,
+    pub response_bytes: usize,
+    pub duration: Duration,
+}
+
+impl AccessLogEntry {
+    /// Format the entry as a single line, without the trailing newline
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Common => format!(
+                "{} - - [{}] \"{} {} jsontp/{}\" {} {} {} {} {}",
+                self.peer,
+                self.timestamp.format("%d/%b/%Y:%H:%M:%S %z"),
+                escape(&self.method),
+                escape(&self.resource),
+                escape(&self.jsontp),
+                self.status,
+                self.response_bytes,
+                self.request_bytes,
+                escape(&self.encoding),
+                self.duration.as_micros(),
+            ),
+            AccessLogFormat::JsonLines => json!({
+                "timestamp": self.timestamp.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                "peer": self.peer.to_string(),
+                "jsontp": self.jsontp,
+                "method": self.method,
+                "resource": self.resource,
+                "status": self.status,
+                "encoding": self.encoding,
+                "request-bytes": self.request_bytes,
+                "response-bytes": self.response_bytes,
+                "duration-us": self.duration.as_micros() as u64,
+            })
+            .to_string(),
+        }
+    }
+}
+
+/// escapes a value from the request for the common format, so that it cannot end the quoted part or the line early
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{{{:x}}}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Writes a line for every request the server handles to a sink, such as a file or stdout
+#[derive(Clone)]
+pub struct AccessLog {
+    sink: Arc<Mutex<Box<dyn Write + Send>>>,
+    format: AccessLogFormat,
+}
+
+impl AccessLog {
+    /// Create a new access log writing to the given sink, in the [`AccessLogFormat::Common`] format
+    pub fn new<W: Write + Send + 'static>(sink: W) -> AccessLog {
+        AccessLog {
+            sink: Arc::new(Mutex::new(Box::new(sink))),
+            format: AccessLogFormat::Common,
+        }
+    }
+
+    /// Change the format of the lines written
+    pub fn format(mut self, format: AccessLogFormat) -> AccessLog {
+        self.format = format;
+        self
+    }
+
+    pub(crate) fn record(&self, entry: &AccessLogEntry) {
+        let line = entry.format(self.format);
+
+        let mut sink = self.sink.lock().unwrap();
+
+        if let Err(e) = writeln!(sink, "{}", line).and_then(|_| sink.flush()) {
+            trace_warn!("failed to write access log: {}", e);
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::middleware::Middleware;
+use crate::shared::{Body, JsontpRequest, Language, Response};
+use crate::status::categorise;
+
+/// Credentials sent by a client in the `authorization` header
+///
+/// the header is an object naming its scheme:
+///
+/// ```json
+/// "authorization": { "scheme": "bearer", "token": "abc123" }
+/// "authorization": { "scheme": "basic", "username": "alice", "password": "hunter2" }
+/// ```
+///
+/// basic credentials are sent as-is, so they should only be used over TLS
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "scheme", rename_all = "lowercase")]
+pub enum Credentials {
+    Bearer { token: String },
+    Basic { username: String, password: String },
+}
+
+impl Credentials {
+    /// The name of the scheme of the credentials, as used in the `authorization` header
+    pub fn scheme(&self) -> &'static str {
+        match self {
+            Credentials::Bearer { .. } => "bearer",
+            Credentials::Basic { .. } => "basic",
+        }
+    }
+}
+
+impl JsontpRequest {
+    /// The credentials sent with the request, if its `authorization` header is present and well formed
+    pub fn credentials(&self) -> Option<Credentials> {
+        serde_json::from_value(self.headers.get("authorization")?.clone()).ok()
+    }
+}
+
+type Verifier = Box<dyn Fn(&Credentials) -> bool + Send + Sync>;
+
+/// Middleware which rejects requests without valid credentials of a single scheme
+///
+/// rejected requests are answered with a 401 whose `www-authenticate` header names the scheme and realm the
+/// client should authenticate with, e.g. `{ "scheme": "bearer", "realm": "jsontp" }`
+pub struct AuthMiddleware {
+    scheme: &'static str,
+    realm: String,
+    verifier: Verifier,
+}
+
+impl AuthMiddleware {
+    /// Require a bearer token which the given function accepts
+    pub fn bearer<F>(verifier: F) -> AuthMiddleware
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        AuthMiddleware {
+            scheme: "bearer",
+            realm: "jsontp".to_string(),
+            verifier: Box::new(move |credentials| match credentials {
+                Credentials::Bearer { token } => verifier(token),
+                _ => false,
+            }),
+        }
+    }
+
+    /// Require a username and password which the given function accepts
+    pub fn basic<F>(verifier: F) -> AuthMiddleware
+    where
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        AuthMiddleware {
+            scheme: "basic",
+            realm: "jsontp".to_string(),
+            verifier: Box::new(move |credentials| match credentials {
+                Credentials::Basic { username, password } => verifier(username, password),
+                _ => false,
+            }),
+        }
+    }
+
+    /// Set the realm sent in the challenge, which is `jsontp` by default
+    pub fn realm<T: ToString>(mut self, realm: T) -> AuthMiddleware {
+        self.realm = realm.to_string();
+        self
+    }
+
+    fn challenge(&self, request: &JsontpRequest) -> Response {
+        let mut response = Response::new_manual(
+            Body::new(categorise(401).human_message, "identity", None),
+            401,
+            None,
+            request.resource.clone(),
+            Language::default(),
+            None,
+        );
+
+        let mut challenge = serde_json::Map::new();
+        challenge.insert("scheme".to_string(), Value::String(self.scheme.to_string()));
+        challenge.insert("realm".to_string(), Value::String(self.realm.clone()));
+
+        response.set_header("www-authenticate", Value::Object(challenge));
+
+        response
+    }
+}
+
+impl Middleware for AuthMiddleware {
+    fn before(&self, request: &mut JsontpRequest) -> Option<Response> {
+        match request.credentials() {
+            Some(credentials) if (self.verifier)(&credentials) => None,
+            _ => Some(self.challenge(request)),
+        }
+    }
+}
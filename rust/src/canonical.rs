@@ -0,0 +1,89 @@
+use serde::Serialize;
+use serde_json::{Number, Value};
+
+use crate::shared::{JsontpRequest, JsontpResponse};
+
+/// Serialize a value to canonical JSON, the same bytes for any two equal values
+///
+/// canonical JSON has object keys sorted by their UTF-8 bytes, no insignificant whitespace, and numbers written in
+/// their shortest form, with floats that hold a whole number written as integers (so `1.0` becomes `1` and `-0.0`
+/// becomes `0`)
+pub fn to_canonical_string<T: Serialize + ?Sized>(value: &T) -> Result<String, String> {
+    let value = serde_json::to_value(value).map_err(|e| format!("Error serializing value: {}", e))?;
+
+    let mut out = String::new();
+    write_value(&mut out, &value);
+
+    Ok(out)
+}
+
+/// Serialize a value to canonical JSON bytes, see [`to_canonical_string`]
+pub fn to_canonical_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, String> {
+    to_canonical_string(value).map(String::into_bytes)
+}
+
+impl JsontpRequest {
+    /// The request as canonical JSON, see [`to_canonical_string`]
+    pub fn to_canonical_string(&self) -> String {
+        to_canonical_string(self).unwrap()
+    }
+}
+
+impl JsontpResponse {
+    /// The response as canonical JSON, see [`to_canonical_string`]
+    pub fn to_canonical_string(&self) -> String {
+        to_canonical_string(self).unwrap()
+    }
+}
+
+fn write_value(out: &mut String, value: &Value) {
+    match value {
+        Value::Null | Value::Bool(_) | Value::String(_) => out.push_str(&value.to_string()),
+        Value::Number(number) => write_number(out, number),
+        Value::Array(values) => {
+            out.push('[');
+
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+
+                write_value(out, value);
+            }
+
+            out.push(']');
+        }
+        Value::Object(map) => {
+            // sorted here rather than relying on `Map`, which keeps insertion order if any crate in the build
+            // enables serde_json's `preserve_order` feature
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+
+            out.push('{');
+
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write_value(out, value);
+            }
+
+            out.push('}');
+        }
+    }
+}
+
+/// the largest integer a float holds exactly, beyond which whole floats keep their float form
+const MAX_SAFE_INTEGER: f64 = 9007199254740991.0;
+
+fn write_number(out: &mut String, number: &Number) {
+    match number.as_f64() {
+        Some(float) if number.is_f64() && float.fract() == 0.0 && float.abs() <= MAX_SAFE_INTEGER => {
+            out.push_str(&(float as i64).to_string())
+        }
+        _ => out.push_str(&number.to_string()),
+    }
+}
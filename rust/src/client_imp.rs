@@ -2,11 +2,40 @@ use crate::shared::*;
 
 use serde_json::Value;
 
-use std::{collections::HashMap, io::{Read, Write}};
+use std::collections::HashMap;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::transport::*;
+use crate::metrics::{Metrics, RequestMetrics, Side};
+use crate::cookie::CookieJar;
+use crate::auth::Credentials;
+use crate::signing::Keyring;
+use crate::retry::RetryPolicy;
+use crate::redirect::{is_redirect, redirect_method, Location};
+use crate::encoding::EncodingRegistry;
 
 /// A jsontp request object
+#[derive(Clone)]
 pub struct Request {
     pub(crate) inner: JsontpRequest,
+    pub(crate) metrics: Option<Arc<dyn Metrics>>,
+    pub(crate) cookie_jar: Option<CookieJar>,
+    pub(crate) signing: Option<Keyring>,
+    pub(crate) retry: Option<RetryPolicy>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) max_redirects: Option<u32>,
+    pub(crate) max_message_size: usize,
+    pub(crate) encodings: EncodingRegistry,
+    /// why the body could not be set, reported when the request is sent
+    pub(crate) body_error: Option<String>,
+}
+
+impl Default for Request {
+    fn default() -> Self {
+        Request::new()
+    }
 }
 
 impl Request {
@@ -20,7 +49,20 @@ impl Request {
                 resource: "/".to_string(),
                 headers: HashMap::new(),
                 body: Body::new("", "identity", None),
+                session: None,
+                peer: None,
+                params: Vec::new(),
+                state: None,
             },
+            metrics: None,
+            cookie_jar: None,
+            signing: None,
+            retry: None,
+            timeout: None,
+            max_redirects: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            encodings: EncodingRegistry::new(),
+            body_error: None,
         }
     }
 
@@ -44,43 +86,455 @@ impl Request {
 
     /// Set the body of the request
     pub fn body<T: ToString, U: ToString>(mut self, content: T, encoding: U) -> Request {
-        self.inner.body.content = content.to_string();
+        self.inner.body.set_text(content.to_string());
         self.inner.body.encoding = encoding.to_string();
         self
     }
 
+    /// Set the body of the request to the given bytes, see [`Body::from_bytes`]
+    pub fn bytes(mut self, bytes: &[u8]) -> Request {
+        let body = Body::from_bytes(bytes);
+
+        self.inner.body.content = body.content;
+        self.inner.body.encoding = body.encoding;
+        self.inner.body.other.extend(body.other);
+        self
+    }
+
+    /// Set the body of the request to the given value as JSON, with a `content-type` of `application/json`
+    ///
+    /// if the value cannot be serialized, sending the request fails with the reason
+    pub fn json<T: serde::Serialize + ?Sized>(mut self, value: &T) -> Request {
+        match Body::from_json(value) {
+            Ok(body) => {
+                self.inner.body.set_text(body.content);
+                self.inner.body.encoding = body.encoding;
+                self.body_error = None;
+            }
+            Err(e) => self.body_error = Some(e),
+        }
+
+        self.header("content-type", "application/json")
+    }
+
     /// Set a key in the body of the request
     pub fn body_key<T: ToString>(mut self, key: T, value: Value) -> Request {
         self.inner.body.other .insert(key.to_string(), value);
         self
     }
 
+    /// Send a cookie with the request
+    pub fn cookie<T: ToString, U: ToString>(mut self, name: T, value: U) -> Request {
+        let cookies = self
+            .inner
+            .headers
+            .entry("cookie".to_string())
+            .or_insert_with(|| Value::Object(serde_json::Map::new()));
+
+        if let Value::Object(cookies) = cookies {
+            cookies.insert(name.to_string(), Value::String(value.to_string()));
+        }
+
+        self
+    }
+
+    /// Authenticate the request with a bearer token
+    pub fn bearer_auth<T: ToString>(self, token: T) -> Request {
+        self.credentials(Credentials::Bearer { token: token.to_string() })
+    }
+
+    /// Authenticate the request with a username and password, which are sent as-is, so only use this over TLS
+    pub fn basic_auth<T: ToString, U: ToString>(self, username: T, password: U) -> Request {
+        self.credentials(Credentials::Basic {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    /// Authenticate the request with the given credentials
+    pub fn credentials(mut self, credentials: Credentials) -> Request {
+        self.inner.headers.insert("authorization".to_string(), serde_json::to_value(credentials).unwrap());
+        self
+    }
+
+    /// Send the matching cookies from the jar with the request, and store any cookies the response sets in it
+    pub fn cookie_jar(mut self, jar: &CookieJar) -> Request {
+        self.cookie_jar = Some(jar.clone());
+        self
+    }
+
+    /// Sign the request with the first key of the given keyring just before it is sent, see [`Keyring`]
+    pub fn sign(mut self, keyring: &Keyring) -> Request {
+        self.signing = Some(keyring.clone());
+        self
+    }
+
+    /// Use the given encodings instead of the defaults, see [`EncodingRegistry`]
+    ///
+    /// the body of the request must be in one of them. the server is told it may encode the response with any of
+    /// their codecs in the `accept-encoding` header, unless one is set already, and responses in one of them are
+    /// decoded before they are returned. [`Keyring::verify_response`] still checks the body as it was sent
+    pub fn encodings(mut self, encodings: EncodingRegistry) -> Request {
+        self.encodings = encodings;
+        self
+    }
+
+    /// Record metrics about the request once it is sent
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Request {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Retry the request according to the given policy if it fails, see [`RetryPolicy`]
+    ///
+    /// only requests sent with [`Request::send`], [`Request::send_unix`] or [`Request::send_tls`] are retried, as
+    /// they open a new connection for every attempt
+    pub fn retry(mut self, policy: RetryPolicy) -> Request {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Follow redirects to the resource (and possibly host and port) in their `location` header, up to the given
+    /// number of hops, see [`redirect_method`] for how the method changes
+    ///
+    /// redirects are only followed by [`Request::send`], [`Request::send_unix`] and [`Request::send_tls`]. when
+    /// redirected to another host, the `authorization` and `cookie` headers are dropped and the cookie jar is no
+    /// longer used. revisiting a resource is an error
+    pub fn follow_redirects(mut self, max_hops: u32) -> Request {
+        self.max_redirects = Some(max_hops);
+        self
+    }
+
+    /// Set the largest response to read, in bytes (16 MiB by default)
+    ///
+    /// this applies to the connections the client opens itself; a connection given to [`Request::send_on`] has its
+    /// own limit, see [`Framed::max_message_size`]
+    pub fn max_message_size(mut self, max_message_size: usize) -> Request {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Give up on connecting, sending the request or reading the response if any of them takes longer than this
+    pub fn timeout(mut self, timeout: Duration) -> Request {
+        self.timeout = Some(timeout);
+        self
+    }
+
     /// Send the request to the given host and port
     pub fn send<T: ToString>(self, host: T, port: u16) -> Result<JsontpResponse, String> {
-        let mut client = std::net::TcpStream::connect(format!("{}:{}", host.to_string(), port)).unwrap();
+        self.with_redirects((host.to_string(), port), retarget_tcp, |request, (host, port)| {
+            let address = format!("{}:{}", host, port);
 
-        let request = serde_json::to_string(&self.inner).unwrap();
+            request.with_retries(|request| {
+                let client = request.connect_tcp(&address)?;
 
-        client.write(request.as_bytes()).unwrap();
+                let mut connection = request.framed(client);
+
+                request.exchange(&mut connection, false)
+            })
+        })
+    }
+
+    /// Send the request to the server listening on the unix domain socket at the given path
+    ///
+    /// redirects to another host or port are not followed, as they cannot be reached over the socket
+    #[cfg(unix)]
+    pub fn send_unix<P: AsRef<std::path::Path>>(self, path: P) -> Result<JsontpResponse, String> {
+        let retarget = |path: &std::path::PathBuf, location: &Location| {
+            (location.host.is_none() && location.port.is_none()).then(|| path.clone())
+        };
+
+        self.with_redirects(path.as_ref().to_path_buf(), retarget, |request, path| {
+            request.with_retries(|request| {
+                let client = std::os::unix::net::UnixStream::connect(path)
+                    .and_then(|client| {
+                        client.set_read_timeout(request.timeout)?;
+                        client.set_write_timeout(request.timeout)?;
+                        Ok(client)
+                    })
+                    .map_err(|e| Failure::retryable(format!("Error connecting: {}", e)))?;
+
+                let mut connection = request.framed(client);
+
+                request.exchange(&mut connection, false)
+            })
+        })
+    }
+
+    /// Send the request to the given host and port over TLS, verifying the server against the given configuration
+    #[cfg(feature = "tls")]
+    pub fn send_tls<T: ToString>(self, host: T, port: u16, tls: &crate::tls::ClientTlsConfig) -> Result<JsontpResponse, String> {
+        self.with_redirects((host.to_string(), port), retarget_tcp, |request, (host, port)| {
+            let address = format!("{}:{}", host, port);
+
+            request.with_retries(|request| {
+                let client = request.connect_tcp(&address)?;
+                let client = tls.connect(host, client).map_err(Failure::fatal)?;
+
+                let mut connection = request.framed(client);
+
+                request.exchange(&mut connection, true)
+            })
+        })
+    }
+
+    /// Send the request over an already open connection, such as one from a [`MemoryConnector`]
+    ///
+    /// the connection is treated as insecure, so `secure` cookies from the cookie jar are not sent over it. the
+    /// request is never retried, as the connection cannot be used again
+    pub fn send_on<C: Connection + ?Sized>(self, connection: &mut C) -> Result<JsontpResponse, String> {
+        self.exchange(connection, false).map_err(|failure| failure.message)
+    }
+
+    fn framed<S: Stream>(&self, stream: S) -> Framed<S> {
+        Framed::new(stream).max_message_size(self.max_message_size)
+    }
 
-        let mut request_string = String::new();
+    fn connect_tcp(&self, address: &str) -> Result<TcpStream, Failure> {
+        let connected = match self.timeout {
+            None => TcpStream::connect(address),
+            Some(timeout) => address.to_socket_addrs().and_then(|addresses| {
+                let mut last_error = None;
 
-        let mut buf_reader = std::io::BufReader::new(&client);
+                for address in addresses {
+                    match TcpStream::connect_timeout(&address, timeout) {
+                        Ok(stream) => return Ok(stream),
+                        Err(e) => last_error = Some(e),
+                    }
+                }
+
+                Err(last_error.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no addresses found")))
+            }),
+        };
+
+        connected
+            .and_then(|client| {
+                client.set_read_timeout(self.timeout)?;
+                client.set_write_timeout(self.timeout)?;
+                Ok(client)
+            })
+            .map_err(|e| Failure::retryable(format!("Error connecting: {}", e)))
+    }
+
+    /// sends the request to the target, then follows any redirects, sending each on to wherever `retarget` says
+    /// the location it points to is
+    fn with_redirects<T, R, F>(mut self, mut target: T, retarget: R, mut send: F) -> Result<JsontpResponse, String>
+    where
+        T: Clone + PartialEq,
+        R: Fn(&T, &Location) -> Option<T>,
+        F: FnMut(Request, &T) -> Result<JsontpResponse, String>,
+    {
+        let max_hops = match self.max_redirects {
+            Some(max_hops) => max_hops as usize,
+            None => return send(self, &target),
+        };
+
+        let mut visited = vec![(target.clone(), self.inner.method.clone(), self.inner.resource.clone())];
 
         loop {
-            let mut buffer = [0; 1024];
-            let bytes_read = buf_reader.read(&mut buffer).unwrap();
+            let response = send(self.clone(), &target)?;
+
+            if !is_redirect(response.status.code) {
+                return Ok(response);
+            }
+
+            let location = match response.location() {
+                Some(location) => location,
+                None => return Ok(response),
+            };
+
+            let next = match retarget(&target, &location) {
+                Some(next) => next,
+                None => return Ok(response),
+            };
+
+            if visited.len() > max_hops {
+                return Err(format!("Too many redirects, gave up after {}", max_hops));
+            }
 
-            request_string.push_str(&String::from_utf8_lossy(&buffer[..bytes_read]));
+            let method = redirect_method(response.status.code, &self.inner.method).to_string();
 
-            if bytes_read < 1024 {
-                break;
-            }   
+            if method != self.inner.method {
+                self.inner.method = method;
+                self.inner.body = Body::new("", "identity", None);
+                self.inner.headers.remove("content-type");
+            }
+
+            // credentials and cookies are only meant for the server they were sent to
+            if next != target {
+                self.inner.headers.remove("authorization");
+                self.inner.headers.remove("cookie");
+                self.cookie_jar = None;
+            }
+
+            self.inner.resource = location.resource;
+            target = next;
+
+            let hop = (target.clone(), self.inner.method.clone(), self.inner.resource.clone());
+
+            if visited.contains(&hop) {
+                return Err(format!("Redirect loop detected at {}", self.inner.resource));
+            }
+
+            visited.push(hop);
         }
+    }
+
+    /// makes attempts to send the request until one succeeds or the retry policy gives up
+    fn with_retries<F>(self, mut attempt: F) -> Result<JsontpResponse, String>
+    where
+        F: FnMut(Request) -> Result<JsontpResponse, Failure>,
+    {
+        let policy = match &self.retry {
+            Some(policy) if policy.allows(&self.inner.method) => policy.clone(),
+            _ => return attempt(self).map_err(|failure| failure.message),
+        };
+
+        let mut retry = 0;
 
-        match serde_json::from_str(&request_string) {
-            Ok(response) => Ok(response),
-            Err(e) => Err(format!("Error parsing response: {}", e)),
+        loop {
+            retry += 1;
+
+            let result = attempt(self.clone());
+
+            let retryable = match &result {
+                Ok(response) => policy.statuses.contains(&response.status.code),
+                Err(failure) => failure.retryable,
+            };
+
+            let delay = if retryable && retry < policy.max_attempts {
+                policy.delay(retry, result.as_ref().ok())
+            } else {
+                None
+            };
+
+            match delay {
+                Some(delay) => {
+                    trace_warn!("retrying request for {} in {:?}", self.inner.resource, delay);
+
+                    std::thread::sleep(delay);
+                }
+                None => return result.map_err(|failure| failure.message),
+            }
         }
     }
-}
\ No newline at end of file
+
+    fn exchange<C: Connection + ?Sized>(mut self, connection: &mut C, secure: bool) -> Result<JsontpResponse, Failure> {
+        let started = Instant::now();
+
+        self.prepare(secure).map_err(Failure::fatal)?;
+
+        let request = serde_json::to_string(&self.inner).unwrap();
+
+        connection
+            .write_message(request.as_bytes())
+            .map_err(|e| Failure::retryable(format!("Error sending request: {}", e)))?;
+
+        let message = connection
+            .read_message()
+            .map_err(|e| Failure::retryable(format!("Error reading response: {}", e)))?;
+
+        match serde_json::from_slice::<JsontpResponse>(&message) {
+            Ok(mut response) => {
+                if let Some(metrics) = &self.metrics {
+                    metrics.request_completed(&RequestMetrics {
+                        side: Side::Client,
+                        method: &self.inner.method,
+                        resource: &self.inner.resource,
+                        status: response.status.code,
+                        encoding: &self.inner.body.encoding,
+                        bytes_received: message.len(),
+                        bytes_sent: request.len(),
+                        duration: started.elapsed(),
+                    });
+                }
+
+                self.receive(&mut response).map_err(Failure::fatal)?;
+
+                Ok(response)
+            }
+            Err(e) => {
+                if let Some(metrics) = &self.metrics {
+                    metrics.parse_error(Side::Client);
+                }
+
+                Err(Failure::fatal(format!("Error parsing response: {}", e)))
+            }
+        }
+    }
+
+    /// gets the request ready to send: checks its body, then adds `accept-encoding`, the cookies from the jar and
+    /// the signature
+    pub(crate) fn prepare(&mut self, secure: bool) -> Result<(), String> {
+        if let Some(e) = self.body_error.take() {
+            return Err(e);
+        }
+
+        if !self.encodings.contains(&self.inner.body.encoding) {
+            return Err(format!("Encoding {} is not allowed", self.inner.body.encoding));
+        }
+
+        if !self.inner.headers.contains_key("accept-encoding") {
+            if let Some(accept) = self.encodings.accept_header() {
+                self.inner.headers.insert("accept-encoding".to_string(), accept);
+            }
+        }
+
+        if let Some(jar) = &self.cookie_jar {
+            let mut cookies = jar.cookies_for(&self.inner.resource, secure);
+
+            // cookies set on the request itself take precedence over the jar
+            if let Some(Value::Object(explicit)) = self.inner.headers.get("cookie") {
+                for (name, value) in explicit {
+                    cookies.insert(name.clone(), value.as_str().unwrap_or_default().to_string());
+                }
+            }
+
+            if !cookies.is_empty() {
+                self.inner.headers.insert("cookie".to_string(), serde_json::to_value(cookies).unwrap());
+            }
+        }
+
+        // signing comes last, so that it covers everything which is sent
+        if let Some(keyring) = &self.signing {
+            keyring.sign_request(&mut self.inner)?;
+        }
+
+        Ok(())
+    }
+
+    /// handles the response to the request: stores the cookies it sets in the jar, and decodes its body
+    pub(crate) fn receive(&self, response: &mut JsontpResponse) -> Result<(), String> {
+        if let Some(jar) = &self.cookie_jar {
+            jar.store(response.cookies());
+        }
+
+        // the signature of the response covers the body as it was sent
+        if response.body.encoding != "identity" && self.encodings.get(&response.body.encoding).is_some() {
+            response.encoded_body = Some(response.body.clone());
+        }
+
+        self.encodings.decode(&mut response.body)
+    }
+}
+
+/// redirects over TCP go to the host and port of the location, defaulting to those already in use
+fn retarget_tcp((host, port): &(String, u16), location: &Location) -> Option<(String, u16)> {
+    Some((location.host.clone().unwrap_or_else(|| host.clone()), location.port.unwrap_or(*port)))
+}
+
+/// why an attempt to send a request failed, and whether trying again could help
+struct Failure {
+    message: String,
+    retryable: bool,
+}
+
+impl Failure {
+    fn retryable(message: String) -> Failure {
+        Failure { message, retryable: true }
+    }
+
+    fn fatal(message: String) -> Failure {
+        Failure { message, retryable: false }
+    }
+}
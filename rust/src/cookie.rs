@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::shared::{JsontpRequest, JsontpResponse};
+
+/// The `same-site` attribute of a cookie
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// A cookie set by a server, with its attributes
+///
+/// responses carry the cookies they set in the `set-cookie` header, as an array of objects:
+///
+/// ```json
+/// "set-cookie": [
+///     {
+///         "name": "session",
+///         "value": "abc123",
+///         "expires": "2024-01-01T00:00:00Z",
+///         "max-age": 3600,
+///         "path": "/",
+///         "secure": true,
+///         "http-only": true,
+///         "same-site": "strict"
+///     }
+/// ]
+/// ```
+///
+/// every field but `name` and `value` is optional. clients send cookies back in the `cookie` header, as an object
+/// mapping each name to its value
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+    /// how many seconds the cookie lives for, taking precedence over `expires`; zero or less removes the cookie
+    #[serde(default, rename = "max-age", skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub secure: bool,
+    #[serde(default, rename = "http-only", skip_serializing_if = "is_false")]
+    pub http_only: bool,
+    #[serde(default, rename = "same-site", skip_serializing_if = "Option::is_none")]
+    pub same_site: Option<SameSite>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl Cookie {
+    /// Create a new cookie with the given name and value, and no attributes
+    pub fn new<T: ToString, U: ToString>(name: T, value: U) -> Cookie {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            expires: None,
+            max_age: None,
+            path: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// Set when the cookie expires
+    pub fn expires(mut self, expires: DateTime<Utc>) -> Cookie {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// Set how many seconds the cookie lives for
+    pub fn max_age(mut self, seconds: i64) -> Cookie {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    /// Only send the cookie with requests for resources under the given path
+    pub fn path<T: ToString>(mut self, path: T) -> Cookie {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    /// Only send the cookie over TLS
+    pub fn secure(mut self, secure: bool) -> Cookie {
+        self.secure = secure;
+        self
+    }
+
+    /// Mark the cookie as not to be exposed to scripts
+    pub fn http_only(mut self, http_only: bool) -> Cookie {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Set the `same-site` attribute of the cookie
+    pub fn same_site(mut self, same_site: SameSite) -> Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// Whether the cookie should be sent with a request for the given resource
+    fn matches(&self, resource: &str) -> bool {
+        let path = match &self.path {
+            Some(path) => path.as_str(),
+            None => return true,
+        };
+
+        let resource = resource.split_once('?').map_or(resource, |(resource, _)| resource);
+
+        match resource.strip_prefix(path) {
+            Some(rest) => path.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+impl JsontpRequest {
+    /// The cookies sent with the request, from its `cookie` header
+    ///
+    /// for compatibility, a `"name=value; other=value"` string is accepted as well as an object
+    pub fn cookies(&self) -> HashMap<String, String> {
+        match self.headers.get("cookie") {
+            Some(Value::Object(map)) => map
+                .iter()
+                .map(|(name, value)| {
+                    let value = match value {
+                        Value::String(s) => s.clone(),
+                        other => other.to_string(),
+                    };
+
+                    (name.clone(), value)
+                })
+                .collect(),
+            Some(Value::String(s)) => s
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                .collect(),
+            _ => HashMap::new(),
+        }
+    }
+
+    /// The value of the cookie with the given name, if it was sent
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies().remove(name)
+    }
+}
+
+impl JsontpResponse {
+    /// The cookies set by the response, from its `set-cookie` header; malformed entries are skipped
+    pub fn cookies(&self) -> Vec<Cookie> {
+        match self.headers.get("set-cookie") {
+            Some(Value::Array(cookies)) => cookies
+                .iter()
+                .filter_map(|cookie| serde_json::from_value(cookie.clone()).ok())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+struct StoredCookie {
+    cookie: Cookie,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl StoredCookie {
+    fn is_live(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+/// A store of the cookies set by a server, which sends them back with later requests
+///
+/// jars are cheap to clone, with every clone sharing the same cookies. a jar does not keep track of which server
+/// set each cookie, so use one jar per server
+#[derive(Clone, Default)]
+pub struct CookieJar {
+    cookies: Arc<Mutex<Vec<StoredCookie>>>,
+}
+
+impl CookieJar {
+    /// Create a new, empty cookie jar
+    pub fn new() -> CookieJar {
+        CookieJar::default()
+    }
+
+    /// Store the given cookies, replacing any with the same name and path, and removing any which have expired
+    pub fn store(&self, cookies: Vec<Cookie>) {
+        let now = Utc::now();
+
+        let mut stored = self.cookies.lock().unwrap();
+
+        for cookie in cookies {
+            stored.retain(|existing| existing.cookie.name != cookie.name || existing.cookie.path != cookie.path);
+
+            let expires_at = match cookie.max_age {
+                Some(seconds) => match TimeDelta::try_seconds(seconds).and_then(|age| now.checked_add_signed(age)) {
+                    Some(expires_at) => Some(expires_at),
+                    // too far off to represent, so the cookie either never expires or has already expired
+                    None if seconds > 0 => None,
+                    None => Some(DateTime::<Utc>::MIN_UTC),
+                },
+                None => cookie.expires,
+            };
+
+            stored.push(StoredCookie { cookie, expires_at });
+        }
+
+        stored.retain(|existing| existing.is_live(now));
+    }
+
+    /// The unexpired cookie with the given name, if there is one
+    pub fn get(&self, name: &str) -> Option<Cookie> {
+        let now = Utc::now();
+
+        self.cookies
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|stored| stored.cookie.name == name && stored.is_live(now))
+            .map(|stored| stored.cookie.clone())
+    }
+
+    /// The names and values of the cookies to send with a request for the given resource
+    ///
+    /// cookies marked `secure` are only included if the request is sent over TLS
+    pub fn cookies_for(&self, resource: &str, secure: bool) -> HashMap<String, String> {
+        let now = Utc::now();
+
+        self.cookies
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|stored| stored.is_live(now))
+            .filter(|stored| secure || !stored.cookie.secure)
+            .filter(|stored| stored.cookie.matches(resource))
+            .map(|stored| (stored.cookie.name.clone(), stored.cookie.value.clone()))
+            .collect()
+    }
+
+    /// Remove every cookie from the jar
+    pub fn clear(&self) {
+        self.cookies.lock().unwrap().clear();
+    }
+}
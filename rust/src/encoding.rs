@@ -0,0 +1,155 @@
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::shared::Body;
+
+/// A codec for body content, named by the body's `encoding` field, e.g. `zstd` or `base64`
+///
+/// encoded content must still be a string, as it is sent inside a JSON message
+pub trait Encoding: Send + Sync {
+    /// The name of the encoding, as used in the `encoding` field and the `accept-encoding` header
+    fn name(&self) -> &str;
+
+    /// Encode plain content
+    fn encode(&self, content: &str) -> Result<String, String>;
+
+    /// Decode content back into plain content, failing if it is not valid in this encoding
+    fn decode(&self, content: &str) -> Result<String, String>;
+}
+
+/// The `identity` encoding, where content is sent as-is
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Identity;
+
+impl Encoding for Identity {
+    fn name(&self) -> &str {
+        "identity"
+    }
+
+    fn encode(&self, content: &str) -> Result<String, String> {
+        Ok(content.to_string())
+    }
+
+    fn decode(&self, content: &str) -> Result<String, String> {
+        Ok(content.to_string())
+    }
+}
+
+/// The encodings a client or server understands
+///
+/// an encoding is either a codec, which the registry can encode and decode content with, or just a name, for content
+/// which the application encodes and decodes itself. by default, `identity` is a codec and `gzip`, `deflate` and
+/// `br` are names. a body in any other encoding is rejected
+#[derive(Clone)]
+pub struct EncodingRegistry {
+    codecs: Vec<Arc<dyn Encoding>>,
+    names: Vec<String>,
+}
+
+impl Default for EncodingRegistry {
+    fn default() -> Self {
+        EncodingRegistry::new()
+    }
+}
+
+impl core::fmt::Debug for EncodingRegistry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EncodingRegistry")
+            .field("codecs", &self.codecs.iter().map(|codec| codec.name()).collect::<Vec<_>>())
+            .field("names", &self.names)
+            .finish()
+    }
+}
+
+impl EncodingRegistry {
+    /// Create a registry with the default encodings
+    pub fn new() -> EncodingRegistry {
+        EncodingRegistry {
+            codecs: vec![Arc::new(Identity)],
+            names: vec!["gzip".to_string(), "deflate".to_string(), "br".to_string()],
+        }
+    }
+
+    /// Add a codec, replacing any encoding with the same name
+    pub fn register<E: Encoding + 'static>(mut self, encoding: E) -> EncodingRegistry {
+        self.remove(encoding.name());
+        self.codecs.push(Arc::new(encoding));
+        self
+    }
+
+    /// Accept bodies in the named encoding, leaving their content for the application to encode and decode
+    pub fn allow<T: ToString>(mut self, name: T) -> EncodingRegistry {
+        let name = name.to_string();
+
+        self.remove(&name);
+        self.names.push(name);
+        self
+    }
+
+    /// Whether bodies in the named encoding are accepted
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some() || self.names.iter().any(|n| n == name)
+    }
+
+    /// The codec for the named encoding, if it has one
+    pub fn get(&self, name: &str) -> Option<&dyn Encoding> {
+        self.codecs.iter().find(|codec| codec.name() == name).map(|codec| codec.as_ref())
+    }
+
+    /// Decode the content of the body, leaving it in the `identity` encoding
+    ///
+    /// bodies in an encoding without a codec are left as they are, and bodies in an unknown encoding are an error
+    pub fn decode(&self, body: &mut Body) -> Result<(), String> {
+        if !self.contains(&body.encoding) {
+            return Err(format!("Encoding {} is not allowed", body.encoding));
+        }
+
+        if let Some(codec) = self.get(&body.encoding) {
+            body.content = codec
+                .decode(&body.content)
+                .map_err(|e| format!("Body is not valid {}: {}", body.encoding, e))?;
+            body.encoding = "identity".to_string();
+        }
+
+        Ok(())
+    }
+
+    /// Encode the content of an `identity` body with the named codec
+    pub fn encode(&self, body: &mut Body, name: &str) -> Result<(), String> {
+        let codec = self.get(name).ok_or_else(|| format!("Encoding {} has no codec", name))?;
+
+        body.content = codec.encode(&body.content)?;
+        body.encoding = name.to_string();
+
+        Ok(())
+    }
+
+    /// The `accept-encoding` header listing every codec, or `None` if there is only `identity`
+    pub(crate) fn accept_header(&self) -> Option<Value> {
+        let names: Vec<Value> = self
+            .codecs
+            .iter()
+            .filter(|codec| codec.name() != "identity")
+            .map(|codec| Value::String(codec.name().to_string()))
+            .collect();
+
+        (!names.is_empty()).then_some(Value::Array(names))
+    }
+
+    /// the first codec in an `accept-encoding` header (a comma-separated string, or an array) which is registered
+    pub(crate) fn negotiate(&self, accept: &Value) -> Option<&dyn Encoding> {
+        let accepted: Vec<&str> = match accept {
+            Value::String(s) => s.split(',').map(str::trim).collect(),
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+
+        accepted.into_iter().find_map(|name| self.get(name))
+    }
+
+    fn remove(&mut self, name: &str) {
+        self.codecs.retain(|codec| codec.name() != name);
+        self.names.retain(|n| n != name);
+    }
+}
@@ -0,0 +1,449 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use serde::de::value::{Error as DeError, MapDeserializer, SeqDeserializer};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde_json::Value;
+
+use crate::into_response::IntoResponse;
+use crate::method::Method;
+use crate::shared::{JsontpRequest, Language, Response};
+
+/// A value which can be taken from a request before it is handled, given as an argument to a route handler
+///
+/// failing to extract a value answers the request with the returned response instead of calling the handler
+pub trait FromRequest: Sized {
+    // the error is the response sent instead, so it is never worth boxing
+    #[allow(clippy::result_large_err)]
+    fn from_request(request: &JsontpRequest) -> Result<Self, Response>;
+}
+
+/// A value which can be taken from a request by consuming it, which is only possible for the last argument of a
+/// handler; every [`FromRequest`] value can be too
+pub trait FromRequestOnce: Sized {
+    #[allow(clippy::result_large_err)]
+    fn from_request_once(request: JsontpRequest) -> Result<Self, Response>;
+}
+
+impl<T: FromRequest> FromRequestOnce for T {
+    fn from_request_once(request: JsontpRequest) -> Result<Self, Response> {
+        T::from_request(&request)
+    }
+}
+
+impl FromRequestOnce for JsontpRequest {
+    fn from_request_once(request: JsontpRequest) -> Result<Self, Response> {
+        Ok(request)
+    }
+}
+
+/// Extracts `None` instead of failing
+impl<T: FromRequest> FromRequest for Option<T> {
+    fn from_request(request: &JsontpRequest) -> Result<Self, Response> {
+        Ok(T::from_request(request).ok())
+    }
+}
+
+/// A function which can handle the requests for a route, taking any number of extracted arguments and returning
+/// anything which is [`IntoResponse`]
+///
+/// every argument but the last must be [`FromRequest`], and the last may be any [`FromRequestOnce`], such as the
+/// whole [`JsontpRequest`]
+pub trait Handler<Args>: Send + Sync + 'static {
+    fn call(&self, request: JsontpRequest) -> Response;
+}
+
+impl<F, R> Handler<()> for F
+where
+    F: Fn() -> R + Send + Sync + 'static,
+    R: IntoResponse,
+{
+    fn call(&self, _request: JsontpRequest) -> Response {
+        self().into_response()
+    }
+}
+
+macro_rules! impl_handler {
+    ($($arg:ident),*; $last:ident) => {
+        #[allow(non_snake_case)]
+        impl<F, R, $($arg,)* $last> Handler<($($arg,)* $last,)> for F
+        where
+            F: Fn($($arg,)* $last) -> R + Send + Sync + 'static,
+            R: IntoResponse,
+            $($arg: FromRequest,)*
+            $last: FromRequestOnce,
+        {
+            fn call(&self, request: JsontpRequest) -> Response {
+                $(
+                    let $arg = match $arg::from_request(&request) {
+                        Ok(value) => value,
+                        Err(response) => return response,
+                    };
+                )*
+
+                let $last = match $last::from_request_once(request) {
+                    Ok(value) => value,
+                    Err(response) => return response,
+                };
+
+                self($($arg,)* $last).into_response()
+            }
+        }
+    };
+}
+
+impl_handler!(; T1);
+impl_handler!(T1; T2);
+impl_handler!(T1, T2; T3);
+impl_handler!(T1, T2, T3; T4);
+impl_handler!(T1, T2, T3, T4; T5);
+impl_handler!(T1, T2, T3, T4, T5; T6);
+
+fn bad_request(message: String) -> Response {
+    Response::builder(400).body(message).build()
+}
+
+/// The parameters matched by `{name}` segments of the route, e.g. `Path<(u32,)>` or `Path<(String, u32)>` in order,
+/// a struct by name, or a single value
+///
+/// parameters are parsed into numbers and booleans as needed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Path<T> {
+    fn from_request(request: &JsontpRequest) -> Result<Self, Response> {
+        T::deserialize(ParamsDeserializer(&request.params))
+            .map(Path)
+            .map_err(|e| bad_request(format!("Invalid path parameters: {}", e)))
+    }
+}
+
+/// The query string of the resource, after its `?`, deserialized into a struct by name
+///
+/// values are percent-decoded, and parsed into numbers and booleans as needed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Query<T> {
+    fn from_request(request: &JsontpRequest) -> Result<Self, Response> {
+        let pairs: Vec<(String, String)> = match request.resource.split_once('?') {
+            Some((_, query)) => query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+                    (percent_decode(key), percent_decode(value))
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        T::deserialize(ParamsDeserializer(&pairs))
+            .map(Query)
+            .map_err(|e| bad_request(format!("Invalid query: {}", e)))
+    }
+}
+
+/// The body of the request read as JSON, failing with a 400 or 422 as described by [`crate::json::JsonError`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(request: &JsontpRequest) -> Result<Self, Response> {
+        request.json().map(Json).map_err(Response::from)
+    }
+}
+
+/// The name of a header, for use with [`Header`], usually declared with [`crate::header_name`]
+pub trait HeaderName {
+    const NAME: &'static str;
+}
+
+/// Declare a type naming a header, to extract it with [`Header`], e.g. `header_name!(pub RequestId = "x-request-id");`
+#[macro_export]
+macro_rules! header_name {
+    ($vis:vis $name:ident = $header:literal) => {
+        $vis struct $name;
+
+        impl $crate::extract::HeaderName for $name {
+            const NAME: &'static str = $header;
+        }
+    };
+}
+
+/// The value of the header named by `N`, failing with a 400 if it is missing
+pub struct Header<N: HeaderName> {
+    pub value: Value,
+    name: PhantomData<N>,
+}
+
+impl<N: HeaderName> Header<N> {
+    /// The value of the header, if it is a string
+    pub fn as_str(&self) -> Option<&str> {
+        self.value.as_str()
+    }
+}
+
+impl<N: HeaderName> FromRequest for Header<N> {
+    fn from_request(request: &JsontpRequest) -> Result<Self, Response> {
+        match request.headers.get(N::NAME) {
+            Some(value) => Ok(Header {
+                value: value.clone(),
+                name: PhantomData,
+            }),
+            None => Err(bad_request(format!("Missing header {}", N::NAME))),
+        }
+    }
+}
+
+/// Shared state given to the server with [`crate::server_imp::Server::state`], failing with a 500 if there is none
+/// of this type
+pub struct State<S>(pub Arc<S>);
+
+impl<S> std::ops::Deref for State<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.0
+    }
+}
+
+impl<S: Send + Sync + 'static> FromRequest for State<S> {
+    fn from_request(request: &JsontpRequest) -> Result<Self, Response> {
+        request
+            .state
+            .as_ref()
+            .and_then(|states| states.get::<S>())
+            .map(State)
+            .ok_or_else(|| {
+                Response::builder(500)
+                    .body(format!("No state of type {}", std::any::type_name::<S>()))
+                    .build()
+            })
+    }
+}
+
+/// The language the client prefers, from the first entry of its `accept-language` header, or `en-US` by default
+impl FromRequest for Language {
+    fn from_request(request: &JsontpRequest) -> Result<Self, Response> {
+        let preferred = match request.headers.get("accept-language") {
+            Some(Value::String(languages)) => languages.split(',').next(),
+            Some(Value::Array(languages)) => languages.first().and_then(Value::as_str),
+            _ => None,
+        };
+
+        // quality values like `;q=0.8` are ignored, only the order counts
+        let preferred = preferred.map(|language| language.split(';').next().unwrap_or_default().trim());
+
+        Ok(match preferred {
+            None | Some("") | Some("*") => Language::default(),
+            Some(language) => match language.split_once(['-', '_']) {
+                Some((lang, locale)) => Language::new(lang, locale),
+                None => Language {
+                    lang: Some(language.to_string()),
+                    locale: None,
+                },
+            },
+        })
+    }
+}
+
+impl FromRequest for Method {
+    fn from_request(request: &JsontpRequest) -> Result<Self, Response> {
+        Ok(request.method())
+    }
+}
+
+/// values of every type given to [`crate::server_imp::Server::state`], by type
+#[derive(Clone, Default)]
+pub(crate) struct StateMap(HashMap<TypeId, Arc<dyn Any + Send + Sync>>);
+
+impl StateMap {
+    pub(crate) fn insert<S: Send + Sync + 'static>(&mut self, state: S) {
+        self.0.insert(TypeId::of::<S>(), Arc::new(state));
+    }
+
+    fn get<S: Send + Sync + 'static>(&self) -> Option<Arc<S>> {
+        self.0.get(&TypeId::of::<S>())?.clone().downcast().ok()
+    }
+}
+
+impl core::fmt::Debug for StateMap {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "StateMap({} values)", self.0.len())
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let s = s.replace('+', " ");
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| s.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+macro_rules! deserialize_single {
+    ($($method:ident),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+                de::Deserializer::$method(self.single()?, visitor)
+            }
+        )*
+    };
+}
+
+/// deserializes named string parameters as a sequence (in order), a map or struct (by name), or a single value
+struct ParamsDeserializer<'a>(&'a [(String, String)]);
+
+impl<'de, 'a> de::Deserializer<'de> for ParamsDeserializer<'a> {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_map(MapDeserializer::new(
+            self.0.iter().map(|(key, value)| (key.as_str(), ParamDeserializer(value))),
+        ))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, DeError> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_seq(SeqDeserializer::new(self.0.iter().map(|(_, value)| ParamDeserializer(value))))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize, visitor: V) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        de::Deserializer::deserialize_enum(self.single()?, name, variants, visitor)
+    }
+
+    deserialize_single! {
+        deserialize_bool, deserialize_i8, deserialize_i16, deserialize_i32, deserialize_i64, deserialize_u8,
+        deserialize_u16, deserialize_u32, deserialize_u64, deserialize_f32, deserialize_f64, deserialize_char,
+        deserialize_str, deserialize_string, deserialize_option,
+    }
+
+    serde::forward_to_deserialize_any! {
+        i128 u128 bytes byte_buf unit unit_struct identifier ignored_any
+    }
+}
+
+impl<'a> ParamsDeserializer<'a> {
+    /// the only parameter, for when a single value is asked for
+    fn single(self) -> Result<ParamDeserializer<'a>, DeError> {
+        match self.0 {
+            [(_, value)] => Ok(ParamDeserializer(value)),
+            params => Err(de::Error::invalid_length(params.len(), &"a single parameter")),
+        }
+    }
+}
+
+/// deserializes a single parameter, parsing it into whatever type is asked for
+struct ParamDeserializer<'a>(&'a str);
+
+impl<'de, 'a> IntoDeserializer<'de, DeError> for ParamDeserializer<'a> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+                match self.0.parse() {
+                    Ok(value) => visitor.$visit(value),
+                    Err(_) => Err(de::Error::invalid_value(de::Unexpected::Str(self.0), &visitor)),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for ParamDeserializer<'a> {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_str(self.0)
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+
+    serde::forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier
+        ignored_any
+    }
+}
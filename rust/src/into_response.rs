@@ -0,0 +1,75 @@
+use serde::Serialize;
+
+use crate::extract::Json;
+use crate::json::JsonError;
+use crate::shared::Response;
+
+/// A value which a route handler can return, to be turned into the response sent to the client
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+/// A 200 response with the string as its body
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        Response::ok(self)
+    }
+}
+
+/// A 200 response with the string as its body
+impl IntoResponse for &str {
+    fn into_response(self) -> Response {
+        Response::ok(self)
+    }
+}
+
+/// The response with its status replaced, e.g. `(201, "Created")`
+impl<T: IntoResponse> IntoResponse for (u16, T) {
+    fn into_response(self) -> Response {
+        let mut response = self.1.into_response();
+
+        response.set_status(self.0);
+
+        response
+    }
+}
+
+/// A 200 response with the value as its JSON body, see [`Response::json`]
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        Response::json(&self.0)
+    }
+}
+
+/// The response for either value, so errors can be returned with `?`
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(error) => error.into_response(),
+        }
+    }
+}
+
+/// The response for the value, or a 404 if there is none
+impl<T: IntoResponse> IntoResponse for Option<T> {
+    fn into_response(self) -> Response {
+        match self {
+            Some(value) => value.into_response(),
+            None => Response::not_found(),
+        }
+    }
+}
+
+/// A 400 or 422 response, see [`JsonError::status`]
+impl IntoResponse for JsonError {
+    fn into_response(self) -> Response {
+        Response::from(self)
+    }
+}
@@ -0,0 +1,79 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::error::Category;
+
+use crate::shared::{Body, JsontpRequest, JsontpResponse, Response};
+
+/// Why a body could not be read as a typed JSON value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonError {
+    /// the content is not valid JSON
+    Syntax(String),
+    /// the content is valid JSON, but not the shape that was expected
+    Data(String),
+}
+
+impl JsonError {
+    /// The status a server answers a request whose body had this error with: 400 for bad syntax, 422 for bad data
+    pub fn status(&self) -> u16 {
+        match self {
+            JsonError::Syntax(_) => 400,
+            JsonError::Data(_) => 422,
+        }
+    }
+}
+
+impl core::fmt::Display for JsonError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            JsonError::Syntax(message) => write!(f, "Body is not valid JSON: {}", message),
+            JsonError::Data(message) => write!(f, "Body does not have the expected fields: {}", message),
+        }
+    }
+}
+
+impl From<JsonError> for Response {
+    fn from(error: JsonError) -> Response {
+        Response::builder(error.status()).body(error).build()
+    }
+}
+
+impl Body {
+    /// A body whose content is the given value as JSON, using the `identity` encoding
+    pub fn from_json<T: Serialize + ?Sized>(value: &T) -> Result<Body, String> {
+        let content = serde_json::to_string(value).map_err(|e| format!("Error serializing body: {}", e))?;
+
+        Ok(Body::new(content, "identity", None))
+    }
+
+    /// Read the content of the body as JSON
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, JsonError> {
+        serde_json::from_str(&self.content).map_err(|e| match e.classify() {
+            Category::Data => JsonError::Data(e.to_string()),
+            Category::Syntax | Category::Eof | Category::Io => JsonError::Syntax(e.to_string()),
+        })
+    }
+}
+
+impl JsontpRequest {
+    /// Read the body of the request as JSON
+    ///
+    /// the error converts into a 400 or 422 response, so handlers can answer with it directly:
+    ///
+    /// ```ignore
+    /// let user: User = match req.json() {
+    ///     Ok(user) => user,
+    ///     Err(e) => return e.into(),
+    /// };
+    /// ```
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, JsonError> {
+        self.body.json()
+    }
+}
+
+impl JsontpResponse {
+    /// Read the body of the response as JSON
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, JsonError> {
+        self.body.json()
+    }
+}
@@ -1,13 +1,55 @@
+#[macro_use]
+mod trace;
 pub(crate) mod shared;
 pub mod server_imp;
 pub mod client_imp;
+pub mod transport;
+pub mod middleware;
+pub mod access_log;
+pub mod metrics;
+pub mod cookie;
+pub mod session;
+pub mod auth;
+pub mod signing;
+pub mod canonical;
+pub mod rate_limit;
+pub mod retry;
+pub mod redirect;
+pub mod json;
+pub mod extract;
+pub mod into_response;
+pub mod method;
+pub mod encoding;
+pub mod testing;
 mod status;
+#[cfg(feature = "tls")]
+pub mod tls;
 
 /// server prelude, containing all the types and traits needed to create a server
 pub mod server {
     pub use crate::shared::*;
     pub use crate::server_imp::*;
+    pub use crate::transport::*;
+    pub use crate::testing::TestClient;
+    pub use crate::middleware::Middleware;
+    pub use crate::access_log::*;
+    pub use crate::metrics::*;
+    pub use crate::cookie::*;
+    pub use crate::session::*;
+    pub use crate::auth::*;
+    pub use crate::signing::*;
+    pub use crate::canonical::*;
+    pub use crate::rate_limit::*;
+    pub use crate::redirect::*;
+    pub use crate::json::*;
+    pub use crate::extract::*;
+    pub use crate::into_response::IntoResponse;
+    pub use crate::method::Method;
+    pub use crate::encoding::*;
+    pub use crate::header_name;
     pub use crate::status::*;
+    #[cfg(feature = "tls")]
+    pub use crate::tls::*;
     pub use serde_json::Value;
 }
 
@@ -15,7 +57,20 @@ pub mod server {
 pub mod client {
     pub use crate::shared::*;
     pub use crate::client_imp::*;
+    pub use crate::metrics::{InMemoryMetrics, Metrics, RequestMetrics, Side};
+    pub use crate::cookie::*;
+    pub use crate::auth::Credentials;
+    pub use crate::signing::Keyring;
+    pub use crate::canonical::*;
+    pub use crate::retry::RetryPolicy;
+    pub use crate::redirect::*;
+    pub use crate::json::*;
+    pub use crate::method::Method;
+    pub use crate::encoding::*;
+    pub use crate::transport::*;
     pub use crate::status::*;
+    #[cfg(feature = "tls")]
+    pub use crate::tls::*;
     pub use serde_json::Value;
 }
 
@@ -28,38 +83,1596 @@ mod tests {
 
     use client::*;
 
+    fn hello(req: JsontpRequest) -> Response {
+        req.to_response(
+            Body::new("Hello, world!", "identity", None),
+            200,
+            None,
+            Language::default(),
+            None
+        )
+    }
+
     #[tokio::test]
     async fn test_server() {
-            let mut server = server_imp::Server::new("hey", "localhost", 8080);
-
-            server.route(
-                "/".to_string(),
-                |req: JsontpRequest| {                    
-                    req.to_response(
-                        Body::new("Hello, world!", "identity", None),
-                        200,
-                        None,
-                        Language::default(),
-                        None
-                    )
-                }
-            );
+            let mut server = server_imp::Server::new("hey", "localhost", 0);
+
+            server.route("/".to_string(), hello);
             
-            server.start();
+            let handle = server.start().unwrap();
 
         println!("hello!");
+
+        let addr = handle.local_addr().unwrap();
+
+        assert_ne!(addr.port(), 0);
+
+        handle.shutdown();
+
+        assert!(std::net::TcpStream::connect(addr).is_err());
     }
 
     #[tokio::test]
     async fn test_client() {
+        let mut server = server_imp::Server::new("hey", "127.0.0.1", 0);
+
+        server.route("/", hello);
+
+        let handle = server.start().unwrap();
+
         let client = Request::new()
             .method("GET")
             .resource("/")
             .header("key1", "value1")
             .body("raw text to be sent", "gzip");
 
-        let response = client.send("localhost", 8080).unwrap();
+        let response = client.send("127.0.0.1", handle.local_addr().unwrap().port()).unwrap();
 
         println!("Server said: {} {}", response.status, response.body.content);
+
+        assert_eq!(response.status.code, 200);
+
+        assert!(handle.shutdown_graceful(std::time::Duration::from_secs(5)));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_on_signal() {
+        let server = server_imp::Server::new("hey", "localhost", 0);
+
+        let handle = server.start().unwrap();
+
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+
+        let shutdown = tokio::spawn(handle.shutdown_on(async { let _ = rx.await; }, std::time::Duration::from_secs(5)));
+
+        tx.send(()).unwrap();
+
+        assert!(shutdown.await.unwrap());
+    }
+
+    #[test]
+    fn test_idle_client() {
+        let mut server = server_imp::Server::new("hey", "127.0.0.1", 0);
+
+        server.route("/", hello);
+        server.read_timeout(Some(std::time::Duration::from_millis(200)));
+
+        let handle = server.start().unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        // connects, but never sends a request
+        let _idle = std::net::TcpStream::connect(addr).unwrap();
+
+        assert_eq!(Request::new().send(addr.ip(), addr.port()).unwrap().status.code, 200);
+
+        let (done, finished) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            handle.shutdown();
+            let _ = done.send(());
+        });
+
+        assert!(finished.recv_timeout(std::time::Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn test_trickling_client() {
+        let mut server = server_imp::Server::new("hey", "127.0.0.1", 0);
+
+        server.route("/", hello);
+        server.read_timeout(Some(std::time::Duration::from_millis(200)));
+
+        let handle = server.start().unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        // sends a byte at a time, each well within the timeout, but never finishes the request
+        let mut trickle = std::net::TcpStream::connect(addr).unwrap();
+
+        let trickler = std::thread::spawn(move || {
+            std::io::Write::write_all(&mut trickle, br#"{"jsontp": ""#)?;
+
+            for _ in 0..100 {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                std::io::Write::write_all(&mut trickle, b"x")?;
+            }
+
+            Ok::<_, std::io::Error>(())
+        });
+
+        assert_eq!(Request::new().send(addr.ip(), addr.port()).unwrap().status.code, 200);
+
+        let (done, finished) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            handle.shutdown();
+            let _ = done.send(());
+        });
+
+        assert!(finished.recv_timeout(std::time::Duration::from_secs(2)).is_ok());
+
+        // the server gave up on the request, so the connection was closed
+        assert!(trickler.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_multiple_addresses() {
+        let mut server = server_imp::Server::new("hey", "127.0.0.1", 0);
+
+        server.route("/", hello);
+        server.listen_on("[::1]:0");
+
+        let handle = server.start().unwrap();
+
+        assert_eq!(handle.local_addrs().len(), 2);
+
+        for addr in handle.local_addrs().iter().filter_map(Address::tcp) {
+            let response = Request::new().send(addr.ip(), addr.port()).unwrap();
+
+            assert_eq!(response.status.code, 200);
+        }
+
+        handle.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        let path = std::env::temp_dir().join(format!("jsontp-test-{}.sock", std::process::id()));
+
+        let mut server = server_imp::Server::new_unix("hey", &path);
+
+        server.route("/", hello);
+
+        let handle = server.start().unwrap();
+
+        assert_eq!(handle.local_addrs(), [Address::Unix(Some(path.clone()))]);
+        assert!(handle.local_addr().is_none());
+
+        let response = Request::new().send_unix(&path).unwrap();
+
+        assert_eq!(response.status.code, 200);
+
+        handle.shutdown();
+
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_test_client() {
+        let mut server = server_imp::Server::new("hey", "localhost", 0);
+        let keyring = Keyring::new().key("k1", b"secret");
+
+        server.route("/", hello);
+        server.route("/login", login);
+        server.route("/other", whoami);
+        server.route("/peer", |req: JsontpRequest| req.peer().map(|peer| peer.to_string()).unwrap_or_default());
+        server.route("/signed", hello);
+        server.route_middleware("/signed", SignatureMiddleware::new(keyring.clone()));
+
+        let client = TestClient::new(&server);
+
+        let response = client.send(Request::new().header("accept-language", "en-US"));
+
+        assert_eq!(response.status.code, 200);
+        assert_eq!(response.body.content, "Hello, world!");
+
+        let response = client.send(Request::new().header("accept-language", "fr-FR"));
+
+        assert_eq!(response.status.code, 406);
+
+        let response = client.send(Request::new().method("FETCH"));
+
+        assert_eq!(response.status.code, 501);
+
+        let response = client.send(Request::new().method("GET ME"));
+
+        assert_eq!(response.status.code, 400);
+
+        let response = client.send(Request::new().resource("/missing"));
+
+        assert_eq!(response.status.code, 404);
+
+        // requests are prepared just as the real client prepares them
+        let jar = CookieJar::new();
+
+        client.send(Request::new().resource("/login").cookie_jar(&jar));
+
+        assert_eq!(client.send(Request::new().resource("/other").cookie_jar(&jar)).body.content, "[theme=dark]");
+        assert_eq!(client.send(Request::new().resource("/signed")).status.code, 401);
+        assert_eq!(client.send(Request::new().resource("/signed").sign(&keyring)).status.code, 200);
+        assert_eq!(client.send(Request::new().resource("/peer")).body.content, client.peer().to_string());
+
+        let unserializable = std::collections::HashMap::from([((1, 2), 3)]);
+
+        assert!(client.try_send(Request::new().json(&unserializable)).is_err());
+    }
+
+    struct RequireKey;
+
+    impl Middleware for RequireKey {
+        fn before(&self, request: &mut JsontpRequest) -> Option<Response> {
+            match request.headers.get("key") {
+                Some(_) => None,
+                None => Some(request.to_response(Body::new("Missing key", "identity", None), 401, None, Language::default(), None)),
+            }
+        }
+    }
+
+    struct Tag(&'static str);
+
+    impl Middleware for Tag {
+        fn before(&self, request: &mut JsontpRequest) -> Option<Response> {
+            if request.resource() == "/old" {
+                request.set_resource("/");
+            }
+
+            None
+        }
+
+        fn after(&self, _request: &JsontpRequest, response: &mut Response) {
+            let tags = match response.get_header("x-tags") {
+                Some(Value::String(tags)) => format!("{},{}", tags, self.0),
+                _ => self.0.to_string(),
+            };
+
+            response.set_header("x-tags", Value::String(tags));
+        }
+    }
+
+    #[test]
+    fn test_middleware() {
+        let mut server = server_imp::Server::new("hey", "localhost", 0);
+
+        server.route("/", hello);
+        server.route("/secret", hello);
+        server.middleware(Tag("global"));
+        server.route_middleware("/secret", Tag("route"));
+        server.route_middleware("/secret", RequireKey);
+
+        let client = TestClient::new(&server);
+
+        let response = client.send(Request::new().resource("/old"));
+
+        assert_eq!(response.status.code, 200);
+        assert_eq!(response.resource, "/");
+        assert_eq!(response.headers["x-tags"], "global");
+
+        let response = client.send(Request::new().resource("/secret"));
+
+        assert_eq!(response.status.code, 401);
+        assert_eq!(response.headers["x-tags"], "route,global");
+
+        let response = client.send(Request::new().resource("/secret").header("key", "1"));
+
+        assert_eq!(response.status.code, 200);
+        assert_eq!(response.headers["x-tags"], "route,global");
+
+        let response = client.send(Request::new().resource("/missing"));
+
+        assert_eq!(response.status.code, 404);
+        assert_eq!(response.headers["x-tags"], "global");
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap().lines().map(String::from).collect()
+        }
+    }
+
+    #[test]
+    fn test_access_log() {
+        let common = SharedBuffer::default();
+        let json = SharedBuffer::default();
+
+        for (buffer, format) in [(&common, AccessLogFormat::Common), (&json, AccessLogFormat::JsonLines)] {
+            let mut server = server_imp::Server::new("hey", "", 0);
+
+            server.route("/", hello);
+            server.access_log(AccessLog::new(buffer.clone()).format(format));
+
+            let (client, server_end) = pipe();
+
+            let worker = std::thread::spawn(move || server.serve_connection(&mut Framed::new(server_end)));
+
+            Request::new().method("POST").body("hi", "gzip").send_on(&mut Framed::new(client)).unwrap();
+
+            worker.join().unwrap();
+        }
+
+        let lines = common.lines();
+
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("memory:"));
+        assert!(lines[0].contains("\"POST / jsontp/1.0-rc1\" 200 "));
+        assert!(lines[0].contains(" gzip "));
+
+        // values from the request cannot forge another line, or end the quoted part early
+        let forged = AccessLogEntry {
+            timestamp: chrono::Utc::now(),
+            peer: Address::Memory(0),
+            jsontp: "1.0-rc1".to_string(),
+            method: "GET\u{7}".to_string(),
+            resource: "/ jsontp/1.0-rc1\" 200 1 1 identity 1\r\nmemory:1 - - [now] \"GET /admin\\".to_string(),
+            status: 404,
+            encoding: "identity".to_string(),
+            request_bytes: 1,
+            response_bytes: 1,
+            duration: std::time::Duration::from_micros(1),
+        };
+
+        let line = forged.format(AccessLogFormat::Common);
+
+        assert!(!line.contains('\n') && !line.contains('\r'));
+        assert!(line.contains("\"GET\\x{7} / jsontp/1.0-rc1\\\" 200 1 1 identity 1\\r\\nmemory:1 - - [now] \\\"GET /admin\\\\ jsontp/1.0-rc1\" 404 "));
+
+        let lines = json.lines();
+
+        assert_eq!(lines.len(), 1);
+
+        let entry: Value = serde_json::from_str(&lines[0]).unwrap();
+
+        assert_eq!(entry["method"], "POST");
+        assert_eq!(entry["resource"], "/");
+        assert_eq!(entry["status"], 200);
+        assert_eq!(entry["encoding"], "gzip");
+        assert!(entry["request-bytes"].as_u64().unwrap() > 0);
+        assert!(entry["response-bytes"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let server_metrics = std::sync::Arc::new(InMemoryMetrics::new());
+        let client_metrics = std::sync::Arc::new(InMemoryMetrics::new());
+
+        let (transport, connector) = MemoryTransport::new();
+
+        let mut server = server_imp::Server::new("hey", "", 0);
+
+        server.route("/", hello);
+        server.route("/users/{id}", hello);
+        server.listen_tcp = false;
+        server.listen_with(transport);
+        server.metrics(server_metrics.clone());
+        server.metrics_route("/metrics", server_metrics.clone());
+
+        let handle = server.start().unwrap();
+
+        for resource in ["/", "/", "/missing"] {
+            Request::new()
+                .resource(resource)
+                .body("hi", "gzip")
+                .metrics(client_metrics.clone())
+                .send_on(&mut connector.connect().unwrap())
+                .unwrap();
+        }
+
+        let mut bad = connector.connect().unwrap();
+
+        bad.write_message(br#"{"jsontp": "1.0-rc1"}"#).unwrap();
+
+        let response: JsontpResponse = serde_json::from_slice(&bad.read_message().unwrap()).unwrap();
+
+        assert_eq!(response.status.code, 400);
+
+        // requests are counted by the route they matched, so clients cannot make up new labels
+        for (method, resource) in [("GET", "/users/1"), ("GET", "/users/2?page=3"), ("GET", "/nowhere"), ("BREW", "/")] {
+            Request::new().method(method).resource(resource).send_on(&mut connector.connect().unwrap()).unwrap();
+        }
+
+        for encoding in ["evil-0", "evil-1"] {
+            Request::new()
+                .body("hi", encoding)
+                .encodings(EncodingRegistry::new().allow(encoding))
+                .send_on(&mut connector.connect().unwrap())
+                .unwrap();
+        }
+
+        let response = Request::new().resource("/metrics").send_on(&mut connector.connect().unwrap()).unwrap();
+
+        handle.shutdown();
+
+        assert_eq!(server_metrics.requests(Side::Server, "GET", "/", "2xx"), 2);
+        assert_eq!(server_metrics.requests(Side::Server, "GET", "unmatched", "4xx"), 2);
+        assert_eq!(server_metrics.requests(Side::Server, "GET", "/users/{id}", "2xx"), 2);
+        assert_eq!(server_metrics.requests(Side::Server, "OTHER", "/", "5xx"), 1);
+        assert_eq!(server_metrics.encoding_usage(Side::Server, "other"), 2);
+        assert_eq!(server_metrics.encoding_usage(Side::Server, "evil-0"), 0);
+        assert_eq!(server_metrics.encoding_usage(Side::Server, "gzip"), 3);
+        assert_eq!(server_metrics.parse_errors(Side::Server), 1);
+        assert_eq!(server_metrics.in_flight(), 0);
+        assert!(server_metrics.bytes_received(Side::Server) > 0);
+
+        assert_eq!(client_metrics.requests(Side::Client, "GET", "/", "2xx"), 2);
+        assert!(client_metrics.bytes_sent(Side::Client) < server_metrics.bytes_received(Side::Server));
+
+        assert_eq!(response.status.code, 200);
+        assert!(response.body.content.contains("jsontp_requests_total{side=\"server\",method=\"GET\",resource=\"/\",status=\"2xx\"} 2"));
+        assert!(response.body.content.contains("jsontp_parse_errors_total{side=\"server\"} 1"));
+        assert!(response.body.content.contains("jsontp_connections_in_flight 1"));
+    }
+
+    fn login(req: JsontpRequest) -> Response {
+        let mut response = req.to_response(Body::new("Logged in", "identity", None), 200, None, Language::default(), None);
+
+        response.set_cookie(Cookie::new("session", "abc").path("/account").http_only(true).same_site(SameSite::Strict));
+        response.set_cookie(Cookie::new("theme", "dark").max_age(3600));
+        response.set_cookie(Cookie::new("token", "xyz").secure(true));
+
+        response
+    }
+
+    fn logout(req: JsontpRequest) -> Response {
+        let mut response = req.to_response(Body::new("Logged out", "identity", None), 200, None, Language::default(), None);
+
+        response.set_cookie(Cookie::new("session", "").path("/account").max_age(0));
+
+        response
+    }
+
+    fn whoami(req: JsontpRequest) -> Response {
+        let cookies = req.cookies();
+
+        let mut names: Vec<_> = cookies.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+        names.sort();
+
+        req.to_response(Body::new(format!("[{}]", names.join(",")), "identity", None), 200, None, Language::default(), None)
+    }
+
+    #[test]
+    fn test_cookies() {
+        let (transport, connector) = MemoryTransport::new();
+
+        let mut server = server_imp::Server::new("hey", "", 0);
+
+        server.route("/login", login);
+        server.route("/logout", logout);
+        server.route("/account", whoami);
+        server.route("/other", whoami);
+        server.listen_tcp = false;
+        server.listen_with(transport);
+
+        let handle = server.start().unwrap();
+
+        let jar = CookieJar::new();
+
+        let send = |request: Request| request.cookie_jar(&jar).send_on(&mut connector.connect().unwrap()).unwrap();
+
+        let response = send(Request::new().resource("/login"));
+
+        assert_eq!(response.headers["set-cookie"][0]["name"], "session");
+        assert_eq!(response.headers["set-cookie"][0]["http-only"], true);
+        assert_eq!(response.headers["set-cookie"][0]["same-site"], "strict");
+        assert_eq!(response.cookies().len(), 3);
+
+        assert_eq!(send(Request::new().resource("/account")).body.content, "[session=abc,theme=dark]");
+        assert_eq!(send(Request::new().resource("/account?tab=1")).body.content, "[session=abc,theme=dark]");
+        assert_eq!(send(Request::new().resource("/other").cookie("extra", "1")).body.content, "[extra=1,theme=dark]");
+
+        send(Request::new().resource("/logout"));
+
+        assert!(jar.get("session").is_none());
+        assert_eq!(send(Request::new().resource("/account")).body.content, "[theme=dark]");
+
+        handle.shutdown();
+
+        // a max-age too large to add to the current time never expires, and one too small has already expired
+        jar.store(vec![Cookie::new("forever", "1").max_age(i64::MAX), Cookie::new("never", "1").max_age(i64::MIN)]);
+
+        assert!(jar.get("forever").is_some());
+        assert!(jar.get("never").is_none());
+
+        jar.store(vec![Cookie::new("large", "1").max_age(i64::MAX / 1000 - 1)]);
+
+        assert!(jar.get("large").is_some());
+    }
+
+    fn visit(req: JsontpRequest) -> Response {
+        let session = req.session().unwrap();
+
+        if req.headers.contains_key("logout") {
+            session.destroy();
+        }
+
+        let visits = session.get("visits").and_then(|v| v.as_u64()).unwrap_or(0) + 1;
+
+        session.insert("visits", visits.into());
+
+        req.to_response(Body::new(visits, "identity", None), 200, None, Language::default(), None)
+    }
+
+    #[test]
+    fn test_sessions() {
+        let directory = std::env::temp_dir().join(format!("jsontp-sessions-{}", std::process::id()));
+
+        let stores: Vec<Box<dyn Fn() -> SessionMiddleware>> = vec![
+            Box::new(|| SessionMiddleware::new(MemorySessionStore::new(), b"secret")),
+            Box::new(|| SessionMiddleware::new(FileSessionStore::new(&directory).unwrap(), b"secret")),
+        ];
+
+        for middleware in stores {
+            let mut server = server_imp::Server::new("hey", "", 0);
+
+            server.route("/", visit);
+            server.middleware(middleware());
+
+            let client = TestClient::new(&server);
+
+            let first = client.send(Request::new());
+
+            assert_eq!(first.body.content, "1");
+
+            let cookie = first.cookies().remove(0);
+
+            assert_eq!(cookie.name, "session");
+            assert!(cookie.http_only);
+
+            let second = client.send(Request::new().cookie("session", &cookie.value));
+
+            assert_eq!(second.body.content, "2");
+            assert!(second.cookies().is_empty());
+
+            let (id, _) = cookie.value.split_once('.').unwrap();
+            let forged = format!("{}.{}", id, "00".repeat(32));
+
+            assert_eq!(client.send(Request::new().cookie("session", forged)).body.content, "1");
+
+            let logout = client.send(Request::new().cookie("session", &cookie.value).header("logout", "1"));
+
+            assert_eq!(logout.cookies()[0].max_age, Some(0));
+            assert_eq!(client.send(Request::new().cookie("session", &cookie.value)).body.content, "1");
+        }
+
+        // sessions which go unused for too long are forgotten, while using one keeps it alive
+        let idle = std::time::Duration::from_millis(300);
+
+        let stores: Vec<Box<dyn SessionStore>> = vec![
+            Box::new(MemorySessionStore::new().max_idle(idle)),
+            Box::new(FileSessionStore::new(&directory).unwrap().max_idle(idle)),
+        ];
+
+        for store in stores {
+            let data = SessionData::from([("visits".to_string(), Value::from(1))]);
+
+            store.save("abc", &data).unwrap();
+
+            for _ in 0..3 {
+                std::thread::sleep(std::time::Duration::from_millis(150));
+
+                assert_eq!(store.load("abc"), Some(data.clone()));
+            }
+
+            std::thread::sleep(std::time::Duration::from_millis(400));
+
+            assert_eq!(store.load("abc"), None);
+        }
+
+        assert!(!directory.join("abc.json").exists());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_auth() {
+        let mut server = server_imp::Server::new("hey", "", 0);
+
+        server.route("/", hello);
+        server.route("/admin", hello);
+        server.route_middleware("/", AuthMiddleware::bearer(|token| token == "letmein"));
+        server.route_middleware("/admin", AuthMiddleware::basic(|user, pass| user == "admin" && pass == "hunter2").realm("admin"));
+
+        let client = TestClient::new(&server);
+
+        let missing = client.send(Request::new());
+
+        assert_eq!(missing.status.code, 401);
+        assert_eq!(missing.status.formal_message, "Unauthorized");
+        assert_eq!(missing.headers["www-authenticate"]["scheme"], "bearer");
+        assert_eq!(missing.headers["www-authenticate"]["realm"], "jsontp");
+
+        assert_eq!(client.send(Request::new().bearer_auth("wrong")).status.code, 401);
+        assert_eq!(client.send(Request::new().bearer_auth("letmein")).status.code, 200);
+
+        let wrong_scheme = client.send(Request::new().resource("/admin").bearer_auth("letmein"));
+
+        assert_eq!(wrong_scheme.status.code, 401);
+        assert_eq!(wrong_scheme.headers["www-authenticate"]["realm"], "admin");
+
+        assert_eq!(client.send(Request::new().resource("/admin").basic_auth("admin", "nope")).status.code, 401);
+        assert_eq!(client.send(Request::new().resource("/admin").basic_auth("admin", "hunter2")).status.code, 200);
+
+        let request = Request::new().basic_auth("admin", "hunter2").inner;
+
+        assert_eq!(request.headers["authorization"]["scheme"], "basic");
+        assert_eq!(
+            request.credentials(),
+            Some(Credentials::Basic { username: "admin".to_string(), password: "hunter2".to_string() })
+        );
+    }
+
+    #[test]
+    fn test_signing() {
+        let old = Keyring::new().key("old", b"old secret");
+        let rotated = Keyring::new().key("new", b"new secret").key("old", b"old secret");
+
+        let mut server = server_imp::Server::new("hey", "", 0);
+
+        server.route("/", hello);
+        server.middleware(SignatureMiddleware::new(rotated.clone()));
+        server.sign_responses(rotated.clone());
+
+        let (transport, connector) = MemoryTransport::new();
+
+        server.listen_tcp = false;
+        server.listen_with(transport);
+
+        let handle = server.start().unwrap();
+
+        let send = |request: Request| request.send_on(&mut connector.connect().unwrap()).unwrap();
+
+        let unsigned = send(Request::new());
+
+        assert_eq!(unsigned.status.code, 401);
+        assert_eq!(unsigned.body.content, "Message is not signed");
+
+        // both the current and the previous key are accepted
+        let response = send(Request::new().header("x", "1").sign(&rotated));
+
+        assert_eq!(response.status.code, 200);
+        assert_eq!(response.headers["signature"]["key-id"], "new");
+        assert!(rotated.verify_response(&response).is_ok());
+        assert!(old.verify_response(&response).is_err());
+
+        assert_eq!(send(Request::new().sign(&old)).status.code, 200);
+        assert_eq!(send(Request::new().sign(&Keyring::new().key("old", b"wrong"))).body.content, "Invalid signature");
+
+        handle.shutdown();
+
+        // tampering with anything but the signature breaks it
+        let mut request = Request::new().body("hello", "identity").inner;
+        rotated.sign_request(&mut request).unwrap();
+
+        assert!(rotated.verify_request(&request).is_ok());
+
+        let mut tampered = request.clone();
+        tampered.body.content = "goodbye".to_string();
+
+        assert_eq!(rotated.verify_request(&tampered), Err("Invalid signature".to_string()));
+
+        let mut tampered = request.clone();
+        tampered.headers.insert("extra".to_string(), Value::Bool(true));
+
+        assert!(rotated.verify_request(&tampered).is_err());
+
+        // so does signing a message dated too far in the past
+        let mut stale = Request::new().header("date", "2020-01-01T00:00:00Z+0000").inner;
+        rotated.sign_request(&mut stale).unwrap();
+
+        assert!(rotated.verify_request(&stale).unwrap_err().contains("too far"));
+        assert!(rotated.clone().max_skew(std::time::Duration::MAX).verify_request(&stale).is_ok());
+    }
+
+    #[test]
+    fn test_canonical() {
+        let first = Request::new().header("b", "2").header("a", "1").body_key("z", Value::Null).body_key("y", Value::Null).inner;
+        let second = Request::new().body_key("y", Value::Null).body_key("z", Value::Null).header("a", "1").header("b", "2").inner;
+
+        assert_eq!(first.to_canonical_string(), second.to_canonical_string());
+        assert_eq!(
+            first.to_canonical_string(),
+            r#"{"body":{"content":"","encoding":"identity","y":null,"z":null},"headers":{"a":"1","b":"2"},"jsontp":"1.0-rc1","method":"GET","resource":"/","type":"request"}"#
+        );
+
+        let value: Value = serde_json::from_str(r#"{ "b": [1.0, -0.0, 1.5, 1e300, -7], "a": { "d": "\n\u00e9", "c": true } }"#).unwrap();
+
+        assert_eq!(to_canonical_string(&value).unwrap(), r#"{"a":{"c":true,"d":"\né"},"b":[1,0,1.5,1e+300,-7]}"#);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut server = server_imp::Server::new("hey", "127.0.0.1", 0);
+
+        server.route("/", hello);
+        server.route("/keyed", hello);
+        server.route("/custom", hello);
+        server.route_middleware("/", RateLimiter::new(Quota::new(2, std::time::Duration::from_secs(60))));
+        server.route_middleware("/keyed", RateLimiter::new(Quota::per_minute(1)).key_by_header("api-key"));
+        server.route_middleware(
+            "/custom",
+            RateLimiter::new(Quota::per_minute(1)).key_by(|req| req.body.other.get("user").map(|user| user.to_string())),
+        );
+
+        // the peer is only known over a real connection
+        let handle = server.clone().start().unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        let send = |request: Request| request.send(addr.ip(), addr.port()).unwrap();
+
+        assert_eq!(send(Request::new()).status.code, 200);
+        assert_eq!(send(Request::new()).status.code, 200);
+
+        let limited = send(Request::new());
+
+        assert_eq!(limited.status.code, 429);
+        assert_eq!(limited.status.formal_message, "Too Many Requests");
+        assert_eq!(limited.headers["retry-after"], 30);
+
+        handle.shutdown();
+
+        let client = TestClient::new(&server);
+
+        let keyed = |key: &str| client.send(Request::new().resource("/keyed").header("api-key", key)).status.code;
+
+        assert_eq!(keyed("a"), 200);
+        assert_eq!(keyed("b"), 200);
+        assert_eq!(keyed("a"), 429);
+
+        // requests without a key are not limited
+        assert_eq!(client.send(Request::new().resource("/keyed")).status.code, 200);
+        assert_eq!(client.send(Request::new().resource("/keyed")).status.code, 200);
+
+        let custom = |user: &str| client.send(Request::new().resource("/custom").body_key("user", user.into())).status.code;
+
+        assert_eq!(custom("alice"), 200);
+        assert_eq!(custom("alice"), 429);
+        assert_eq!(custom("bob"), 200);
+
+        let store = MemoryRateLimitStore::new();
+        let quota = Quota::new(1, std::time::Duration::from_millis(20));
+
+        assert!(store.acquire("x", &quota).is_ok());
+        assert!(store.acquire("x", &quota).is_err());
+
+        std::thread::sleep(std::time::Duration::from_millis(30));
+
+        assert!(store.acquire("x", &quota).is_ok());
+
+        // past the limit on buckets, the least recently used is dropped
+        let store = MemoryRateLimitStore::new().max_buckets(2);
+        let quota = Quota::per_minute(1);
+
+        assert!(store.acquire("a", &quota).is_ok());
+        assert!(store.acquire("b", &quota).is_ok());
+        assert!(store.acquire("a", &quota).is_err());
+        assert!(store.acquire("c", &quota).is_ok());
+        assert!(store.acquire("a", &quota).is_err());
+        assert!(store.acquire("b", &quota).is_ok());
+        assert_eq!(store.buckets(), 2);
+
+        // and many keys cost no more than a few
+        let store = MemoryRateLimitStore::new().max_buckets(100);
+
+        for key in 0..10_000 {
+            assert!(store.acquire(&key.to_string(), &quota).is_ok());
+        }
+
+        assert_eq!(store.buckets(), 100);
+    }
+
+    static FLAKY_CALLS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    fn flaky(req: JsontpRequest) -> Response {
+        let calls = FLAKY_CALLS.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+
+        if calls < 3 {
+            let mut response = req.to_response(Body::new("Try again", "identity", None), 503, None, Language::default(), None);
+
+            if calls == 1 {
+                response.set_header("retry-after", "0".into());
+            }
+
+            return response;
+        }
+
+        req.to_response(Body::new("Finally", "identity", None), 200, None, Language::default(), None)
+    }
+
+    fn busy(req: JsontpRequest) -> Response {
+        let mut response = req.to_response(Body::new("Busy", "identity", None), 429, None, Language::default(), None);
+
+        response.set_header("retry-after", 60.into());
+
+        response
+    }
+
+    #[test]
+    fn test_retry() {
+        let mut server = server_imp::Server::new("hey", "127.0.0.1", 0);
+
+        server.route("/flaky", flaky);
+        server.route("/busy", busy);
+
+        let handle = server.start().unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        let policy = RetryPolicy::new().backoff(std::time::Duration::from_millis(1), std::time::Duration::from_millis(10));
+        let reset = || FLAKY_CALLS.store(0, std::sync::atomic::Ordering::SeqCst);
+        let calls = || FLAKY_CALLS.load(std::sync::atomic::Ordering::SeqCst);
+
+        let response = Request::new().resource("/flaky").retry(policy.clone()).send(addr.ip(), addr.port()).unwrap();
+
+        assert_eq!(response.body.content, "Finally");
+        assert_eq!(calls(), 3);
+
+        reset();
+
+        let response = Request::new().resource("/flaky").retry(policy.clone().max_attempts(2)).send(addr.ip(), addr.port()).unwrap();
+
+        assert_eq!(response.status.code, 503);
+        assert_eq!(calls(), 2);
+
+        // POST is not idempotent, so it is only retried when asked for
+        reset();
+
+        let post = Request::new().method("POST").resource("/flaky").body("x", "identity");
+
+        assert_eq!(post.clone().retry(policy.clone()).send(addr.ip(), addr.port()).unwrap().status.code, 503);
+        assert_eq!(calls(), 1);
+
+        reset();
+
+        assert_eq!(post.retry(policy.clone().any_method(true)).send(addr.ip(), addr.port()).unwrap().status.code, 200);
+
+        // a retry-after longer than the policy allows is returned straight away
+        let started = std::time::Instant::now();
+
+        assert_eq!(Request::new().resource("/busy").retry(policy.clone()).send(addr.ip(), addr.port()).unwrap().status.code, 429);
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+
+        handle.shutdown();
+
+        // connection errors are retried with backoff
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let started = std::time::Instant::now();
+
+        let no_jitter = RetryPolicy::new()
+            .backoff(std::time::Duration::from_millis(20), std::time::Duration::from_secs(1))
+            .jitter(false);
+
+        assert!(Request::new().retry(no_jitter).send(closed.ip(), closed.port()).unwrap_err().starts_with("Error connecting"));
+        assert!(started.elapsed() >= std::time::Duration::from_millis(60));
+
+        // a server which never answers times out
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+
+        let error = Request::new()
+            .timeout(std::time::Duration::from_millis(50))
+            .send(silent_addr.ip(), silent_addr.port())
+            .unwrap_err();
+
+        assert!(error.starts_with("Error reading response"));
+    }
+
+    static OTHER_PORT: std::sync::atomic::AtomicU16 = std::sync::atomic::AtomicU16::new(0);
+
+    fn redirect_to(req: JsontpRequest, status: u16, location: Value) -> Response {
+        let mut response = req.to_response(Body::new("Moved", "identity", None), status, None, Language::default(), None);
+
+        response.set_header("location", location);
+
+        response
+    }
+
+    fn echo_method(req: JsontpRequest) -> Response {
+        let authorized = req.credentials().is_some();
+        let content = format!("{} {} {}", req.method, req.body.content, authorized);
+
+        req.to_response(Body::new(content, "identity", None), 200, None, Language::default(), None)
+    }
+
+    #[test]
+    fn test_redirects() {
+        let mut other = server_imp::Server::new("other", "127.0.0.1", 0);
+
+        other.route("/there", echo_method);
+        other.route("/cookies", whoami);
+
+        let other = other.start().unwrap();
+
+        OTHER_PORT.store(other.local_addr().unwrap().port(), std::sync::atomic::Ordering::SeqCst);
+
+        let mut server = server_imp::Server::new("hey", "127.0.0.1", 0);
+
+        server.route("/method", echo_method);
+        server.route("/moved", |req| redirect_to(req, 301, "/method".into()));
+        server.route("/see-other", |req| redirect_to(req, 303, "/method".into()));
+        server.route("/temporary", |req| redirect_to(req, 307, "/method".into()));
+        server.route("/chain", |req| redirect_to(req, 302, "/moved".into()));
+        server.route("/loop-a", |req| redirect_to(req, 302, "/loop-b".into()));
+        server.route("/loop-b", |req| redirect_to(req, 302, "/loop-a".into()));
+        server.route("/elsewhere", |req| {
+            let port = OTHER_PORT.load(std::sync::atomic::Ordering::SeqCst);
+
+            redirect_to(req, 307, Location { resource: "/there".to_string(), host: Some("127.0.0.1".to_string()), port: Some(port) }.to_value())
+        });
+        server.route("/elsewhere-cookies", |req| {
+            let port = OTHER_PORT.load(std::sync::atomic::Ordering::SeqCst);
+
+            redirect_to(req, 307, Location { resource: "/cookies".to_string(), host: Some("127.0.0.1".to_string()), port: Some(port) }.to_value())
+        });
+        server.route("/login", login);
+        server.route("/cookies", whoami);
+        server.route("/here-cookies", |req| redirect_to(req, 307, "/cookies".into()));
+        server.route("/content-type", |req: JsontpRequest| req.headers.contains_key("content-type").to_string());
+        server.route("/moved-json", |req| redirect_to(req, 301, "/content-type".into()));
+
+        let handle = server.start().unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        let send = |request: Request| request.send(addr.ip(), addr.port());
+
+        // redirects are only followed when asked for
+        let response = send(Request::new().resource("/moved")).unwrap();
+
+        assert_eq!(response.status.code, 301);
+        assert_eq!(response.location(), Some(Location::resource("/method")));
+
+        let post = || Request::new().method("POST").body("data", "identity").follow_redirects(5);
+
+        assert_eq!(send(post().resource("/moved")).unwrap().body.content, "GET  false");
+        assert_eq!(send(post().resource("/see-other")).unwrap().body.content, "GET  false");
+        assert_eq!(send(post().resource("/temporary")).unwrap().body.content, "POST data false");
+        assert_eq!(send(post().resource("/chain")).unwrap().body.content, "GET  false");
+
+        assert_eq!(send(post().resource("/chain").follow_redirects(1)).unwrap_err(), "Too many redirects, gave up after 1");
+        assert_eq!(send(Request::new().resource("/loop-a").follow_redirects(5)).unwrap_err(), "Redirect loop detected at /loop-a");
+
+        // credentials stay with the server they were meant for
+        assert_eq!(send(post().resource("/temporary").bearer_auth("secret")).unwrap().body.content, "POST data true");
+        assert_eq!(send(post().resource("/elsewhere").bearer_auth("secret")).unwrap().body.content, "POST data false");
+
+        // and so do cookies, whether from the jar or set on the request
+        let jar = CookieJar::new();
+
+        send(Request::new().resource("/login").cookie_jar(&jar)).unwrap();
+
+        let with_cookies = |resource: &str| Request::new().resource(resource).cookie_jar(&jar).cookie("extra", "1").follow_redirects(5);
+
+        assert_eq!(send(with_cookies("/here-cookies")).unwrap().body.content, "[extra=1,theme=dark]");
+        assert_eq!(send(with_cookies("/elsewhere-cookies")).unwrap().body.content, "[]");
+
+        // the content type goes along with the body when the method is rewritten
+        let json = Request::new().method("POST").json(&User { name: "ann".to_string(), age: 30 }).resource("/moved-json");
+
+        assert_eq!(send(json.follow_redirects(5)).unwrap().body.content, "false");
+
+        handle.shutdown();
+        other.shutdown();
+    }
+
+    #[test]
+    fn test_response_helpers() {
+        let mut server = server_imp::Server::new("hey", "", 0);
+
+        server.route("/ok", || Response::ok("fine"));
+        server.route("/json", || Response::json(&serde_json::json!({ "a": [1, 2] })));
+        server.route("/bad-json", || Response::json(&std::collections::HashMap::from([((1, 2), 3)])));
+        server.route("/permanent", || Response::redirect("/ok", true));
+        server.route("/temporary", || {
+            Response::redirect(Location { resource: "/ok".to_string(), host: Some("example.com".to_string()), port: None }, false)
+        });
+        server.route("/empty", Response::no_content);
+        server.route("/gone", Response::not_found);
+        server.route("/built", || {
+            Response::builder(201)
+                .body("made")
+                .encoding("gzip")
+                .body_key("id", 7.into())
+                .header("x-made", true.into())
+                .cookie(Cookie::new("made", "yes"))
+                .language(Language::new("fr", "FR"))
+                .build()
+        });
+
+        let client = TestClient::new(&server);
+        let send = |resource: &str| client.send(Request::new().resource(resource));
+
+        let ok = send("/ok");
+
+        assert_eq!((ok.status.code, ok.body.content.as_str(), ok.body.encoding.as_str()), (200, "fine", "identity"));
+        assert_eq!(ok.resource, "/ok");
+
+        let json = send("/json");
+
+        assert_eq!(json.body.content, r#"{"a":[1,2]}"#);
+        assert_eq!(json.headers["content-type"], "application/json");
+        assert_eq!(send("/bad-json").status.code, 500);
+
+        let permanent = send("/permanent");
+
+        assert_eq!(permanent.status.code, 308);
+        assert_eq!(permanent.location(), Some(Location::resource("/ok")));
+
+        let temporary = send("/temporary");
+
+        assert_eq!(temporary.status.code, 307);
+        assert_eq!(temporary.location().unwrap().host.as_deref(), Some("example.com"));
+
+        let empty = send("/empty");
+
+        assert_eq!((empty.status.code, empty.body.content.as_str()), (204, ""));
+        assert_eq!(send("/gone").status.code, 404);
+
+        let built = send("/built");
+
+        assert_eq!((built.status.code, built.body.encoding.as_str()), (201, "gzip"));
+        assert_eq!(built.body.other["id"], 7);
+        assert_eq!(built.headers["x-made"], true);
+        assert_eq!(built.headers["language"], "fr-FR");
+        assert_eq!(built.cookies()[0].name, "made");
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    fn create_user(req: JsontpRequest) -> Response {
+        let user: User = match req.json() {
+            Ok(user) => user,
+            Err(e) => return e.into(),
+        };
+
+        Response::json(&User { name: user.name.to_uppercase(), age: user.age + 1 })
+    }
+
+    #[test]
+    fn test_json_bodies() {
+        let mut server = server_imp::Server::new("hey", "", 0);
+
+        server.route("/users", create_user);
+
+        let client = TestClient::new(&server);
+
+        let request = Request::new().method("POST").resource("/users").json(&User { name: "ann".to_string(), age: 30 });
+
+        assert_eq!(request.inner.headers["content-type"], "application/json");
+
+        let response = client.send(request);
+
+        assert_eq!(response.status.code, 200);
+        assert_eq!(response.json::<User>().unwrap(), User { name: "ANN".to_string(), age: 31 });
+
+        let invalid = client.send(Request::new().method("POST").resource("/users").body("{\"name\": ", "identity"));
+
+        assert_eq!(invalid.status.code, 400);
+        assert!(invalid.body.content.starts_with("Body is not valid JSON"));
+
+        let missing = client.send(Request::new().method("POST").resource("/users").json(&serde_json::json!({ "name": "ann" })));
+
+        assert_eq!(missing.status.code, 422);
+        assert!(missing.body.content.contains("missing field `age`"));
+
+        assert!(matches!(Body::new("[1, 2]", "identity", None).json::<User>(), Err(JsonError::Data(_))));
+
+        let (_transport, connector) = MemoryTransport::new();
+
+        let unserializable = Request::new().json(&std::collections::HashMap::from([((1, 2), 3)]));
+
+        assert!(unserializable.send_on(&mut connector.connect().unwrap()).unwrap_err().starts_with("Error serializing body"));
+    }
+
+    header_name!(RequestId = "x-request-id");
+
+    struct Greeting {
+        text: &'static str,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Page {
+        page: u32,
+        sort: Option<String>,
+    }
+
+    fn show_user(Path((id,)): Path<(u32,)>) -> Response {
+        Response::ok(format!("user {}", id))
+    }
+
+    fn show_post(Path((user, post)): Path<(String, u32)>, Query(page): Query<Page>) -> Response {
+        Response::ok(format!("{} {} {} {:?}", user, post, page.page, page.sort))
+    }
+
+    fn greet(greeting: State<Greeting>, language: Language, id: Option<Header<RequestId>>) -> Response {
+        let id = id.map(|id| id.value.to_string()).unwrap_or_default();
+
+        Response::ok(format!("{} {} {}", greeting.text, language, id))
+    }
+
+    fn rename(Json(user): Json<User>, req: JsontpRequest) -> Response {
+        Response::ok(format!("{} at {}", user.name, req.resource()))
+    }
+
+    #[test]
+    fn test_extractors() {
+        let mut server = server_imp::Server::new("hey", "", 0);
+
+        server.route("/users/me", || Response::ok("me"));
+        server.route("/users/{id}", show_user);
+        server.route("/users/{user}/posts/{post}", show_post);
+        server.route("/greet", greet);
+        server.route("/rename", rename);
+        server.route_middleware("/users/{id}", Tag("user"));
+        server.state(Greeting { text: "hello" });
+
+        let client = TestClient::new(&server);
+        let send = |request: Request| client.send(request);
+        let get = |resource: &str| send(Request::new().resource(resource));
+
+        let user = get("/users/7");
+
+        assert_eq!(user.body.content, "user 7");
+        assert_eq!(user.headers["x-tags"], "user");
+        assert_eq!(get("/users/me").body.content, "me");
+
+        let invalid = get("/users/abc");
+
+        assert_eq!(invalid.status.code, 400);
+        assert!(invalid.body.content.starts_with("Invalid path parameters"));
+
+        assert_eq!(get("/users/ann/posts/3?page=2&sort=new%20est").body.content, r#"ann 3 2 Some("new est")"#);
+        assert_eq!(get("/users/ann/posts/3?page=1").body.content, "ann 3 1 None");
+        assert!(get("/users/ann/posts/3").body.content.starts_with("Invalid query"));
+        assert_eq!(get("/users/ann/posts").status.code, 404);
+
+        assert_eq!(get("/greet").body.content, "hello en-US ");
+        assert_eq!(
+            send(Request::new().resource("/greet").header("accept-language", "fr-FR, en;q=0.5").header("x-request-id", "abc")).body.content,
+            "hello fr-FR \"abc\""
+        );
+
+        let renamed = send(Request::new().resource("/rename").json(&User { name: "ann".to_string(), age: 1 }));
+
+        assert_eq!(renamed.body.content, "ann at /rename");
+        assert_eq!(send(Request::new().resource("/rename").body("nope", "identity")).status.code, 400);
+
+        // state which was never given is a server error
+        let mut stateless = server_imp::Server::new("hey", "", 0);
+
+        stateless.route("/greet", greet);
+
+        assert_eq!(TestClient::new(&stateless).send(Request::new().resource("/greet")).status.code, 500);
+    }
+
+    fn find_user(Path((id,)): Path<(u32,)>) -> Option<Json<User>> {
+        (id == 1).then(|| Json(User { name: "ann".to_string(), age: 30 }))
+    }
+
+    fn create_checked(Json(user): Json<User>) -> Result<(u16, String), (u16, &'static str)> {
+        match user.age {
+            0 => Err((422, "Age must be positive")),
+            _ => Ok((201, format!("Created {}", user.name))),
+        }
+    }
+
+    fn parse_user(req: JsontpRequest) -> Result<Json<User>, JsonError> {
+        Ok(Json(req.json()?))
+    }
+
+    #[test]
+    fn test_into_response() {
+        let mut server = server_imp::Server::new("hey", "", 0);
+
+        server.route("/str", || "plain");
+        server.route("/string", || String::from("owned"));
+        server.route("/status", || (418, "teapot"));
+        server.route("/users/{id}", find_user);
+        server.route("/users", create_checked);
+        server.route("/parse", parse_user);
+
+        let client = TestClient::new(&server);
+        let send = |request: Request| client.send(request);
+        let get = |resource: &str| send(Request::new().resource(resource));
+
+        assert_eq!((get("/str").status.code, get("/str").body.content), (200, "plain".to_string()));
+        assert_eq!(get("/string").body.content, "owned");
+        assert_eq!((get("/status").status.code, get("/status").body.content), (418, "teapot".to_string()));
+
+        assert_eq!(get("/users/1").json::<User>().unwrap(), User { name: "ann".to_string(), age: 30 });
+        assert_eq!(get("/users/2").status.code, 404);
+
+        let created = send(Request::new().resource("/users").json(&User { name: "bo".to_string(), age: 3 }));
+
+        assert_eq!((created.status.code, created.body.content), (201, "Created bo".to_string()));
+        assert_eq!(send(Request::new().resource("/users").json(&User { name: "bo".to_string(), age: 0 })).status.code, 422);
+
+        assert_eq!(send(Request::new().resource("/parse").json(&User { name: "cy".to_string(), age: 9 })).json::<User>().unwrap().name, "cy");
+        assert_eq!(send(Request::new().resource("/parse").body("{", "identity")).status.code, 400);
+    }
+
+    #[test]
+    fn test_methods() {
+        let mut server = server_imp::Server::new("hey", "", 0);
+
+        server.route("/", |method: Method| Response::builder(200).body(method).header("x-seen", serde_json::json!("yes")).build());
+        server.route("/read-only", hello);
+        server.route_methods("/read-only", &[Method::Get]);
+        server.extension_method("PURGE");
+
+        let client = TestClient::new(&server);
+        let send = |method: Method, resource: &str| client.send(Request::new().method(method).resource(resource));
+
+        let patched = send(Method::Patch, "/");
+
+        assert_eq!((patched.status.code, patched.body.content), (200, "PATCH".to_string()));
+        assert_eq!(send(Method::from("PURGE"), "/").status.code, 200);
+        assert_eq!(send(Method::from("BREW"), "/").status.code, 501);
+
+        let options = send(Method::Options, "/read-only");
+
+        assert_eq!(options.status.code, 204);
+        assert_eq!(options.headers["allow"], serde_json::json!(["GET", "HEAD", "OPTIONS"]));
+
+        let denied = send(Method::Post, "/read-only");
+
+        assert_eq!(denied.status.code, 405);
+        assert_eq!(denied.headers["allow"], options.headers["allow"]);
+
+        assert!(send(Method::Options, "/").headers["allow"].as_array().unwrap().contains(&"PURGE".into()));
+
+        let head = send(Method::Head, "/");
+
+        assert_eq!((head.status.code, head.body.content.as_str()), (200, ""));
+        assert_eq!(head.headers["x-seen"], "yes");
+    }
+
+    struct Reverse;
+
+    impl Encoding for Reverse {
+        fn name(&self) -> &str {
+            "reverse"
+        }
+
+        fn encode(&self, content: &str) -> Result<String, String> {
+            Ok(content.chars().rev().collect())
+        }
+
+        fn decode(&self, content: &str) -> Result<String, String> {
+            Ok(content.chars().rev().collect())
+        }
+    }
+
+    fn echo(req: JsontpRequest) -> String {
+        format!("{} as {}", req.body.content, req.body.encoding)
+    }
+
+    #[test]
+    fn test_encodings() {
+        let (transport, connector) = MemoryTransport::new();
+
+        let mut server = server_imp::Server::new("hey", "", 0);
+
+        server.route("/echo", echo);
+        server.encodings(EncodingRegistry::new().register(Reverse));
+
+        let client = TestClient::new(&server);
+
+        // the client passes the encodings through as they are, so what the server sends back can be checked
+        let send = |request: Request| client.send(request.resource("/echo").encodings(EncodingRegistry::new().allow("reverse").allow("zstd")));
+
+        let plain = send(Request::new().body("olleh", "reverse"));
+
+        assert_eq!((plain.body.content.as_str(), plain.body.encoding.as_str()), ("hello as identity", "identity"));
+
+        let encoded = send(Request::new().body("olleh", "reverse").header("accept-encoding", "br, reverse"));
+
+        assert_eq!((encoded.body.content.as_str(), encoded.body.encoding.as_str()), ("ytitnedi sa olleh", "reverse"));
+
+        assert_eq!(send(Request::new().body("abc", "gzip")).body.content, "abc as gzip");
+        assert_eq!(send(Request::new().body("abc", "zstd")).status.code, 400);
+
+        // signatures cover the body as it was sent, even once the client has decoded it
+        let keyring = Keyring::new().key("k1", b"secret");
+
+        server.sign_responses(keyring.clone());
+
+        let client = TestClient::new(&server);
+
+        let signed = client.send(Request::new().resource("/echo").body("olleh", "reverse").encodings(EncodingRegistry::new().register(Reverse)));
+
+        assert_eq!((signed.body.content.as_str(), signed.body.encoding.as_str()), ("hello as identity", "identity"));
+        assert_eq!(keyring.verify_response(&signed), Ok(()));
+
+        let mut tampered = signed;
+        tampered.encoded_body.as_mut().unwrap().content = "olleh".to_string();
+
+        assert!(keyring.verify_response(&tampered).is_err());
+
+        server.listen_tcp = false;
+        server.listen_with(transport);
+
+        let handle = server.start().unwrap();
+
+        let registry = EncodingRegistry::new().register(Reverse);
+
+        let response = Request::new()
+            .resource("/echo")
+            .body("olleh", "reverse")
+            .encodings(registry.clone())
+            .send_on(&mut connector.connect().unwrap())
+            .unwrap();
+
+        assert_eq!((response.body.content.as_str(), response.body.encoding.as_str()), ("hello as identity", "identity"));
+
+        let unknown = Request::new().body("abc", "zstd").send_on(&mut connector.connect().unwrap());
+
+        assert_eq!(unknown.unwrap_err(), "Encoding zstd is not allowed");
+
+        handle.shutdown();
+    }
+
+    fn reversed_bytes(req: JsontpRequest) -> Response {
+        match req.body.bytes() {
+            Ok(mut bytes) => {
+                bytes.reverse();
+                Response::builder(200).bytes(&bytes).build()
+            }
+            Err(e) => Response::builder(400).body(e).build(),
+        }
+    }
+
+    #[test]
+    fn test_binary_bodies() {
+        assert_eq!(Body::from_bytes(b"Man").content, "TWFu");
+        assert_eq!(Body::from_bytes(b"Ma").content, "TWE=");
+        assert_eq!(Body::from_bytes(b"M").content, "TQ==");
+        assert_eq!(Body::from_bytes(b"").content, "");
+
+        let binary: Vec<u8> = (0..=255).collect();
+
+        for len in [0, 1, 2, 3, 4, 5, 256] {
+            assert_eq!(Body::from_bytes(&binary[..len]).bytes().unwrap(), &binary[..len]);
+        }
+
+        assert_eq!(Body::new("hi", "identity", None).bytes().unwrap(), b"hi");
+
+        let mut broken = Body::from_bytes(b"abc");
+        broken.content = "YW=j".to_string();
+
+        assert!(broken.bytes().is_err());
+
+        let mut server = server_imp::Server::new("hey", "", 0);
+
+        server.route("/reverse", reversed_bytes);
+
+        let client = TestClient::new(&server);
+
+        let response = client.send(Request::new().resource("/reverse").bytes(&[0xff, 0x00, 0x80]));
+
+        assert_eq!(response.body.other["content-transfer"], "base64");
+        assert_eq!(response.body.bytes().unwrap(), [0x80, 0x00, 0xff]);
+
+        // setting text content afterwards drops the marker
+        let response = client.send(Request::new().resource("/reverse").bytes(&[0xff]).body("ab", "identity"));
+
+        assert_eq!(response.body.bytes().unwrap(), b"ba");
+
+        let (client, server_end) = pipe();
+
+        let worker = std::thread::spawn(move || server.serve_connection(&mut Framed::new(server_end)));
+
+        let mut raw = br#"{"jsontp":"1.0-rc1","type":"request","method":"GET","resource":"/reverse","headers":{},"body":{"content":"?","encoding":"identity"}}"#.to_vec();
+        let question = raw.iter().position(|&b| b == b'?').unwrap();
+        raw[question] = 0xff;
+
+        let mut client = Framed::new(client);
+
+        client.write_message(&raw).unwrap();
+
+        let response: JsontpResponse = serde_json::from_slice(&client.read_message().unwrap()).unwrap();
+
+        assert_eq!(response.status.code, 400);
+        assert!(response.body.content.contains("not valid UTF-8"));
+
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn test_memory_transport() {
+        let (transport, connector) = MemoryTransport::new();
+
+        let mut server = server_imp::Server::new("hey", "", 0);
+
+        server.route("/", hello);
+        server.listen_tcp = false;
+        server.listen_with(transport);
+
+        let handle = server.start().unwrap();
+
+        for _ in 0..3 {
+            let response = Request::new().send_on(&mut connector.connect().unwrap()).unwrap();
+
+            assert_eq!(response.status.code, 200);
+            assert_eq!(response.body.content, "Hello, world!");
+        }
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_serve_connection() {
+        let mut server = server_imp::Server::new("hey", "", 0);
+
+        server.route("/", hello);
+
+        let (client, server_end) = pipe();
+
+        let worker = std::thread::spawn(move || server.serve_connection(&mut Framed::new(server_end)));
+
+        let big = "x".repeat(10_000);
+
+        let response = Request::new()
+            .resource("/missing")
+            .body(&big, "identity")
+            .send_on(&mut Framed::new(client))
+            .unwrap();
+
+        assert_eq!(response.status.code, 404);
+
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn test_framing() {
+        let (mut a, b) = pipe();
+
+        std::io::Write::write_all(&mut a, br#" {"a": "}{\"", "b": [{}]}{"c": 1}"#).unwrap();
+        drop(a);
+
+        let mut b = Framed::new(b);
+
+        assert_eq!(b.read_message().unwrap(), br#"{"a": "}{\"", "b": [{}]}"#);
+        assert_eq!(b.read_message().unwrap(), br#"{"c": 1}"#);
+        assert!(b.read_message().is_err());
+
+        // a peer cannot make the other end buffer an endless message
+        let (mut a, b) = pipe();
+
+        let writer = std::thread::spawn(move || {
+            std::io::Write::write_all(&mut a, br#"{"a": ""#)?;
+
+            loop {
+                std::io::Write::write_all(&mut a, &[b'x'; 1024])?;
+            }
+        });
+
+        let mut b = Framed::new(b).max_message_size(64 * 1024);
+
+        assert_eq!(b.read_message().unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+        drop(b);
+
+        let result: std::io::Result<()> = writer.join().unwrap();
+
+        assert!(result.is_err());
+
+        let (mut a, b) = pipe();
+
+        std::io::Write::write_all(&mut a, br#"{"a": 1}"#).unwrap();
+
+        assert_eq!(Framed::new(b).max_message_size(8).read_message().unwrap(), br#"{"a": 1}"#);
+
+        // the client's limit on responses can be changed
+        let mut server = server_imp::Server::new("hey", "127.0.0.1", 0);
+
+        server.route("/big", || "x".repeat(100_000));
+
+        let handle = server.start().unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        let big = || Request::new().resource("/big");
+
+        assert_eq!(big().send(addr.ip(), addr.port()).unwrap().body.content.len(), 100_000);
+        assert!(big().max_message_size(64 * 1024).send(addr.ip(), addr.port()).unwrap_err().contains("message is larger than 65536 bytes"));
+        assert!(big().max_message_size(1024 * 1024).send(addr.ip(), addr.port()).is_ok());
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_bind_error() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let server = server_imp::Server::new("hey", "127.0.0.1", taken.local_addr().unwrap().port());
+
+        assert!(server.start().is_err());
+    }
+
+    #[cfg(feature = "tls")]
+    fn self_signed() -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+        (
+            cert.der().clone(),
+            rustls::pki_types::PrivatePkcs8KeyDer::from(key_pair.serialize_der()).into(),
+        )
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_tls() {
+        let (cert, key) = self_signed();
+
+        let mut server = server_imp::Server::new("hey", "localhost", 0);
+
+        server.route("/", hello);
+        server.tls(ServerTlsConfig::new(vec![cert.clone()], key).unwrap());
+
+        let handle = server.start().unwrap();
+        let port = handle.local_addr().unwrap().port();
+
+        let trusted = ClientTlsConfig::new().root_certificate(cert).unwrap();
+
+        let response = Request::new().send_tls("localhost", port, &trusted).unwrap();
+
+        assert_eq!(response.status.code, 200);
+        assert_eq!(response.body.content, "Hello, world!");
+
+        assert!(Request::new().send_tls("localhost", port, &ClientTlsConfig::new()).is_err());
+
+        handle.shutdown();
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_tls_client_auth() {
+        let (cert, key) = self_signed();
+        let (client_cert, client_key) = self_signed();
+
+        let mut client_roots = RootCertStore::empty();
+        client_roots.add(client_cert.clone()).unwrap();
+
+        let mut server = server_imp::Server::new("hey", "localhost", 0);
+
+        server.route("/", hello);
+        server.tls(ServerTlsConfig::with_client_auth(vec![cert.clone()], key, client_roots).unwrap());
+
+        let handle = server.start().unwrap();
+        let port = handle.local_addr().unwrap().port();
+
+        let anonymous = ClientTlsConfig::new().root_certificate(cert).unwrap();
+        let identified = anonymous.clone().client_certificate(vec![client_cert], client_key);
+
+        assert!(Request::new().send_tls("localhost", port, &anonymous).is_err());
+
+        let response = Request::new().send_tls("localhost", port, &identified).unwrap();
+
+        assert_eq!(response.status.code, 200);
+
+        handle.shutdown();
     }
 }
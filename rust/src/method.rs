@@ -0,0 +1,90 @@
+use crate::shared::JsontpRequest;
+
+/// The method of a request: one of the standard verbs, or an extension method such as `PURGE`
+///
+/// methods are case-sensitive, so `get` is an extension method rather than `GET`. servers only accept extension
+/// methods registered with [`crate::server_imp::Server::extension_method`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Head,
+    Options,
+    Extension(String),
+}
+
+impl Method {
+    /// Every standard method
+    pub const STANDARD: [Method; 7] = [
+        Method::Get,
+        Method::Post,
+        Method::Put,
+        Method::Delete,
+        Method::Patch,
+        Method::Head,
+        Method::Options,
+    ];
+
+    /// The name of the method, as sent in requests
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Head => "HEAD",
+            Method::Options => "OPTIONS",
+            Method::Extension(name) => name,
+        }
+    }
+
+    /// Whether sending the request more than once has the same effect as sending it once, so it is safe to retry
+    ///
+    /// extension methods are never assumed to be idempotent
+    pub fn is_idempotent(&self) -> bool {
+        matches!(self, Method::Get | Method::Put | Method::Delete | Method::Head | Method::Options)
+    }
+
+    /// Whether the name is a valid method name: non-empty, and only ASCII letters, digits, `-` and `_`
+    pub(crate) fn is_valid(name: &str) -> bool {
+        !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    }
+}
+
+impl From<&str> for Method {
+    fn from(name: &str) -> Method {
+        match name {
+            "GET" => Method::Get,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "PATCH" => Method::Patch,
+            "HEAD" => Method::Head,
+            "OPTIONS" => Method::Options,
+            other => Method::Extension(other.to_string()),
+        }
+    }
+}
+
+impl From<String> for Method {
+    fn from(name: String) -> Method {
+        Method::from(name.as_str())
+    }
+}
+
+impl core::fmt::Display for Method {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl JsontpRequest {
+    /// The method of the request, parsed
+    pub fn method(&self) -> Method {
+        Method::from(self.method.as_str())
+    }
+}
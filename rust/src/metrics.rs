@@ -0,0 +1,294 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::middleware::Middleware;
+use crate::shared::{Body, JsontpRequest, Language, Response};
+
+/// Which end of the exchange a metric was recorded by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Side {
+    Server,
+    Client,
+}
+
+impl Side {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Side::Server => "server",
+            Side::Client => "client",
+        }
+    }
+}
+
+/// Everything measured about a single completed request
+#[derive(Debug, Clone)]
+pub struct RequestMetrics<'a> {
+    pub side: Side,
+    /// the method of the request, or `OTHER` on the server for methods it does not accept
+    pub method: &'a str,
+    /// the resource on the client, and on the server the route it matched, e.g. `/users/{id}`, or `unmatched`
+    pub resource: &'a str,
+    pub status: u16,
+    /// the encoding of the request body, or `other` on the server for encodings it does not accept
+    pub encoding: &'a str,
+    pub bytes_received: usize,
+    pub bytes_sent: usize,
+    pub duration: Duration,
+}
+
+/// A sink for the measurements taken by servers and clients
+///
+/// every method does nothing by default, so implementations only need to handle what they are interested in
+pub trait Metrics: Send + Sync {
+    /// Called when the server accepts a connection
+    fn connection_opened(&self) {}
+
+    /// Called when the server is done with a connection
+    fn connection_closed(&self) {}
+
+    /// Called when a request has been answered
+    fn request_completed(&self, _request: &RequestMetrics) {}
+
+    /// Called when a message could not be parsed, i.e. a bad request on the server or a bad response on the client
+    fn parse_error(&self, _side: Side) {}
+}
+
+/// the upper bounds of the request duration histogram buckets, in seconds
+const DURATION_BUCKETS: [f64; 10] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// keyed by side, method, resource and status class
+    requests: BTreeMap<(Side, String, String, String), u64>,
+    durations: BTreeMap<Side, Histogram>,
+    bytes_received: BTreeMap<Side, u64>,
+    bytes_sent: BTreeMap<Side, u64>,
+    parse_errors: BTreeMap<Side, u64>,
+    encodings: BTreeMap<(Side, String), u64>,
+}
+
+/// Metrics kept in memory, which can be read back directly or rendered in the Prometheus text format
+#[derive(Default)]
+pub struct InMemoryMetrics {
+    in_flight: AtomicI64,
+    state: Mutex<State>,
+}
+
+/// the class of a status code, e.g. `2xx` for 204
+fn status_class(status: u16) -> String {
+    format!("{}xx", status / 100)
+}
+
+impl InMemoryMetrics {
+    /// Create a new, empty set of metrics
+    pub fn new() -> InMemoryMetrics {
+        InMemoryMetrics::default()
+    }
+
+    /// The number of connections the server is currently handling
+    pub fn in_flight(&self) -> i64 {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// The number of requests with the given method and resource whose status was in the given class, e.g. `"2xx"`
+    pub fn requests(&self, side: Side, method: &str, resource: &str, status_class: &str) -> u64 {
+        let key = (side, method.to_string(), resource.to_string(), status_class.to_string());
+
+        self.state.lock().unwrap().requests.get(&key).copied().unwrap_or(0)
+    }
+
+    /// The number of requests whose body used the given encoding
+    pub fn encoding_usage(&self, side: Side, encoding: &str) -> u64 {
+        let key = (side, encoding.to_string());
+
+        self.state.lock().unwrap().encodings.get(&key).copied().unwrap_or(0)
+    }
+
+    /// The number of messages that could not be parsed
+    pub fn parse_errors(&self, side: Side) -> u64 {
+        self.state.lock().unwrap().parse_errors.get(&side).copied().unwrap_or(0)
+    }
+
+    /// The total number of bytes received
+    pub fn bytes_received(&self, side: Side) -> u64 {
+        self.state.lock().unwrap().bytes_received.get(&side).copied().unwrap_or(0)
+    }
+
+    /// The total number of bytes sent
+    pub fn bytes_sent(&self, side: Side) -> u64 {
+        self.state.lock().unwrap().bytes_sent.get(&side).copied().unwrap_or(0)
+    }
+
+    /// Render the metrics in the Prometheus text exposition format
+    pub fn prometheus(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP jsontp_requests_total Requests answered, by method, resource and status class");
+        let _ = writeln!(out, "# TYPE jsontp_requests_total counter");
+
+        for ((side, method, resource, class), count) in &state.requests {
+            let _ = writeln!(
+                out,
+                "jsontp_requests_total{{side=\"{}\",method=\"{}\",resource=\"{}\",status=\"{}\"}} {}",
+                side.as_str(),
+                escape(method),
+                escape(resource),
+                class,
+                count
+            );
+        }
+
+        let _ = writeln!(out, "# HELP jsontp_request_duration_seconds Time taken to answer requests");
+        let _ = writeln!(out, "# TYPE jsontp_request_duration_seconds histogram");
+
+        for (side, histogram) in &state.durations {
+            for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+                let _ = writeln!(
+                    out,
+                    "jsontp_request_duration_seconds_bucket{{side=\"{}\",le=\"{}\"}} {}",
+                    side.as_str(),
+                    bound,
+                    count
+                );
+            }
+
+            let _ = writeln!(out, "jsontp_request_duration_seconds_bucket{{side=\"{}\",le=\"+Inf\"}} {}", side.as_str(), histogram.count);
+            let _ = writeln!(out, "jsontp_request_duration_seconds_sum{{side=\"{}\"}} {}", side.as_str(), histogram.sum);
+            let _ = writeln!(out, "jsontp_request_duration_seconds_count{{side=\"{}\"}} {}", side.as_str(), histogram.count);
+        }
+
+        let _ = writeln!(out, "# HELP jsontp_connections_in_flight Connections currently being handled by the server");
+        let _ = writeln!(out, "# TYPE jsontp_connections_in_flight gauge");
+        let _ = writeln!(out, "jsontp_connections_in_flight {}", self.in_flight());
+
+        for (name, help, values) in [
+            ("jsontp_bytes_received_total", "Bytes of messages received", &state.bytes_received),
+            ("jsontp_bytes_sent_total", "Bytes of messages sent", &state.bytes_sent),
+            ("jsontp_parse_errors_total", "Messages which could not be parsed", &state.parse_errors),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+
+            for (side, value) in values {
+                let _ = writeln!(out, "{}{{side=\"{}\"}} {}", name, side.as_str(), value);
+            }
+        }
+
+        let _ = writeln!(out, "# HELP jsontp_body_encodings_total Requests by body encoding");
+        let _ = writeln!(out, "# TYPE jsontp_body_encodings_total counter");
+
+        for ((side, encoding), count) in &state.encodings {
+            let _ = writeln!(
+                out,
+                "jsontp_body_encodings_total{{side=\"{}\",encoding=\"{}\"}} {}",
+                side.as_str(),
+                escape(encoding),
+                count
+            );
+        }
+
+        out
+    }
+}
+
+/// escapes a Prometheus label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Metrics for InMemoryMetrics {
+    fn connection_opened(&self) {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn connection_closed(&self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn request_completed(&self, request: &RequestMetrics) {
+        let mut state = self.state.lock().unwrap();
+
+        let key = (
+            request.side,
+            request.method.to_string(),
+            request.resource.to_string(),
+            status_class(request.status),
+        );
+
+        *state.requests.entry(key).or_default() += 1;
+        *state.encodings.entry((request.side, request.encoding.to_string())).or_default() += 1;
+        *state.bytes_received.entry(request.side).or_default() += request.bytes_received as u64;
+        *state.bytes_sent.entry(request.side).or_default() += request.bytes_sent as u64;
+
+        state
+            .durations
+            .entry(request.side)
+            .or_default()
+            .observe(request.duration.as_secs_f64());
+    }
+
+    fn parse_error(&self, side: Side) {
+        *self.state.lock().unwrap().parse_errors.entry(side).or_default() += 1;
+    }
+}
+
+/// Middleware which answers requests for a single resource with the Prometheus rendering of some metrics
+pub struct MetricsEndpoint {
+    resource: String,
+    metrics: Arc<InMemoryMetrics>,
+}
+
+impl MetricsEndpoint {
+    /// Serve the given metrics at the given resource
+    pub fn new<T: ToString>(resource: T, metrics: Arc<InMemoryMetrics>) -> MetricsEndpoint {
+        MetricsEndpoint {
+            resource: resource.to_string(),
+            metrics,
+        }
+    }
+}
+
+impl Middleware for MetricsEndpoint {
+    fn before(&self, request: &mut JsontpRequest) -> Option<Response> {
+        if request.resource != self.resource {
+            return None;
+        }
+
+        let mut response = Response::new_manual(
+            Body::new(self.metrics.prometheus(), "identity", None),
+            200,
+            None,
+            request.resource.clone(),
+            Language::default(),
+            None,
+        );
+
+        response.set_header("content-type", "text/plain; version=0.0.4".into());
+
+        Some(response)
+    }
+}
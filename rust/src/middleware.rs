@@ -0,0 +1,20 @@
+use crate::shared::{JsontpRequest, Response};
+
+/// Cross-cutting logic run around request handlers, such as authentication, logging, metrics or adding headers
+///
+/// middleware registered with [`crate::server_imp::Server::middleware`] runs for every request, including ones for
+/// unknown resources, while middleware registered with [`crate::server_imp::Server::route_middleware`] only runs
+/// for its route. `before` hooks run in the order the middleware was registered (global first), and `after` hooks
+/// run in the reverse order
+pub trait Middleware: Send + Sync {
+    /// Called before the request is handled, and may modify it
+    ///
+    /// returning a response skips the handler and any later middleware; the `after` hooks of this and every
+    /// earlier middleware still run
+    fn before(&self, _request: &mut JsontpRequest) -> Option<Response> {
+        None
+    }
+
+    /// Called with the response to the request, which may be modified before it is sent
+    fn after(&self, _request: &JsontpRequest, _response: &mut Response) {}
+}
@@ -0,0 +1,238 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::middleware::Middleware;
+use crate::shared::{Body, JsontpRequest, Language, Response};
+use crate::status::categorise;
+use crate::transport::Address;
+
+/// How many requests a client may make: bursts of up to `capacity`, refilled evenly over `period`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl Quota {
+    /// Allow `capacity` requests every `period`
+    pub fn new(capacity: u32, period: Duration) -> Quota {
+        Quota { capacity, period }
+    }
+
+    /// Allow the given number of requests every second
+    pub fn per_second(capacity: u32) -> Quota {
+        Quota::new(capacity, Duration::from_secs(1))
+    }
+
+    /// Allow the given number of requests every minute
+    pub fn per_minute(capacity: u32) -> Quota {
+        Quota::new(capacity, Duration::from_secs(60))
+    }
+
+    /// how long it takes to refill a single token
+    fn refill_interval(&self) -> Duration {
+        self.period / self.capacity.max(1)
+    }
+}
+
+/// Where the token buckets of a [`RateLimiter`] are kept, e.g. in memory or in a store shared between servers
+pub trait RateLimitStore: Send + Sync {
+    /// Take a token from the bucket for the given key, which starts out full
+    ///
+    /// returns `Err` with how long until a token is available if the bucket is empty
+    fn acquire(&self, key: &str, quota: &Quota) -> Result<(), Duration>;
+}
+
+/// A rate limit store which keeps buckets in memory, so each server limits clients separately
+///
+/// buckets which have filled up again are dropped, as they are the same as new ones. there are at most
+/// [`DEFAULT_MAX_BUCKETS`] buckets unless set with [`MemoryRateLimitStore::max_buckets`], after which the least
+/// recently used are dropped, so a client with many keys can reset the limits of idle clients but not use up memory
+pub struct MemoryRateLimitStore {
+    buckets: Mutex<Buckets>,
+    max_buckets: usize,
+}
+
+/// The number of buckets a [`MemoryRateLimitStore`] keeps by default
+pub const DEFAULT_MAX_BUCKETS: usize = 100_000;
+
+#[derive(Default)]
+struct Buckets {
+    /// the tokens left in each bucket, when that was last worked out, and when it was last used
+    tokens: HashMap<String, (f64, Instant, u64)>,
+    /// the key of every bucket by when it was last used, least recent first
+    by_use: BTreeMap<u64, String>,
+    /// counts up with every use, starting from 1
+    uses: u64,
+}
+
+impl Default for MemoryRateLimitStore {
+    fn default() -> Self {
+        MemoryRateLimitStore::new()
+    }
+}
+
+impl MemoryRateLimitStore {
+    /// Create a new, empty store
+    pub fn new() -> MemoryRateLimitStore {
+        MemoryRateLimitStore {
+            buckets: Mutex::new(Buckets::default()),
+            max_buckets: DEFAULT_MAX_BUCKETS,
+        }
+    }
+
+    /// Set how many buckets to keep at most
+    pub fn max_buckets(mut self, max_buckets: usize) -> MemoryRateLimitStore {
+        self.max_buckets = max_buckets.max(1);
+        self
+    }
+
+    /// The number of buckets being kept
+    pub fn buckets(&self) -> usize {
+        self.buckets.lock().unwrap().tokens.len()
+    }
+}
+
+impl RateLimitStore for MemoryRateLimitStore {
+    fn acquire(&self, key: &str, quota: &Quota) -> Result<(), Duration> {
+        let now = Instant::now();
+        let capacity = quota.capacity as f64;
+        let refill = quota.refill_interval().as_secs_f64();
+
+        let mut state = self.buckets.lock().unwrap();
+        let Buckets { tokens: buckets, by_use, uses } = &mut *state;
+
+        // only the least recently used buckets are looked at, so this takes as long as the number dropped
+        while let Some(oldest) = by_use.first_entry() {
+            let (tokens, updated, _) = buckets[oldest.get()];
+
+            let full = tokens + now.duration_since(updated).as_secs_f64() / refill >= capacity;
+            let room = buckets.len() < self.max_buckets || buckets.contains_key(key);
+
+            if !full && room {
+                break;
+            }
+
+            buckets.remove(&oldest.remove());
+        }
+
+        let (tokens, updated, used) = buckets.entry(key.to_string()).or_insert((capacity, now, 0));
+
+        by_use.remove(used);
+        *uses += 1;
+        *used = *uses;
+        by_use.insert(*used, key.to_string());
+
+        *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() / refill).min(capacity);
+        *updated = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - *tokens) * refill))
+        }
+    }
+}
+
+type KeyFn = Box<dyn Fn(&JsontpRequest) -> Option<String> + Send + Sync>;
+
+/// What requests are grouped by when rate limiting
+pub enum RateLimitKey {
+    /// the address the request came from, ignoring the port of TCP addresses
+    Peer,
+    /// the value of the given header; requests without it are not limited
+    Header(String),
+    /// a key worked out by the given function; requests it returns `None` for are not limited
+    Custom(KeyFn),
+}
+
+impl RateLimitKey {
+    fn of(&self, request: &JsontpRequest) -> Option<String> {
+        match self {
+            RateLimitKey::Peer => Some(match request.peer() {
+                Some(Address::Tcp(addr)) => addr.ip().to_string(),
+                Some(peer) => peer.to_string(),
+                None => "unknown".to_string(),
+            }),
+            RateLimitKey::Header(name) => match request.headers.get(name)? {
+                Value::String(value) => Some(value.clone()),
+                other => Some(other.to_string()),
+            },
+            RateLimitKey::Custom(key) => key(request),
+        }
+    }
+}
+
+/// Middleware which limits how often each client may make requests, using a token bucket per client
+///
+/// requests over the limit are answered with a 429, whose `retry-after` header holds the number of seconds until
+/// the client may try again
+pub struct RateLimiter {
+    quota: Quota,
+    key: RateLimitKey,
+    store: Box<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    /// Limit each peer address to the given quota, keeping buckets in memory
+    pub fn new(quota: Quota) -> RateLimiter {
+        RateLimiter {
+            quota,
+            key: RateLimitKey::Peer,
+            store: Box::new(MemoryRateLimitStore::new()),
+        }
+    }
+
+    /// Change what requests are grouped by
+    pub fn key(mut self, key: RateLimitKey) -> RateLimiter {
+        self.key = key;
+        self
+    }
+
+    /// Group requests by the value of the given header instead of by peer
+    pub fn key_by_header<T: ToString>(self, name: T) -> RateLimiter {
+        self.key(RateLimitKey::Header(name.to_string()))
+    }
+
+    /// Group requests by the key the given function returns for them instead of by peer
+    pub fn key_by<F>(self, key: F) -> RateLimiter
+    where
+        F: Fn(&JsontpRequest) -> Option<String> + Send + Sync + 'static,
+    {
+        self.key(RateLimitKey::Custom(Box::new(key)))
+    }
+
+    /// Keep buckets in the given store instead of in memory
+    pub fn store<S: RateLimitStore + 'static>(mut self, store: S) -> RateLimiter {
+        self.store = Box::new(store);
+        self
+    }
+}
+
+impl Middleware for RateLimiter {
+    fn before(&self, request: &mut JsontpRequest) -> Option<Response> {
+        let key = self.key.of(request)?;
+        let retry_after = self.store.acquire(&key, &self.quota).err()?;
+
+        let mut response = Response::new_manual(
+            Body::new(categorise(429).human_message, "identity", None),
+            429,
+            None,
+            request.resource.clone(),
+            Language::default(),
+            None,
+        );
+
+        // rounded up, so that retrying after that many seconds always succeeds
+        let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+
+        response.set_header("retry-after", seconds.into());
+
+        Some(response)
+    }
+}
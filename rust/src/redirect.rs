@@ -0,0 +1,92 @@
+use serde_json::Value;
+
+use crate::shared::JsontpResponse;
+
+/// Where a redirect points, from the `location` header of a response
+///
+/// the header is either just the new resource, e.g. `"location": "/new"`, or an object which may also name another
+/// host and port, e.g. `"location": { "resource": "/new", "host": "example.com", "port": 8080 }`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub resource: String,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+}
+
+impl Location {
+    /// A location on the same server, at the given resource
+    pub fn resource<T: ToString>(resource: T) -> Location {
+        Location {
+            resource: resource.to_string(),
+            host: None,
+            port: None,
+        }
+    }
+
+    /// The location as a `location` header value, a plain string unless it names a host or port
+    pub fn to_value(&self) -> Value {
+        if self.host.is_none() && self.port.is_none() {
+            return Value::String(self.resource.clone());
+        }
+
+        let mut location = serde_json::Map::new();
+        location.insert("resource".to_string(), Value::String(self.resource.clone()));
+
+        if let Some(host) = &self.host {
+            location.insert("host".to_string(), Value::String(host.clone()));
+        }
+
+        if let Some(port) = self.port {
+            location.insert("port".to_string(), port.into());
+        }
+
+        Value::Object(location)
+    }
+}
+
+impl From<&str> for Location {
+    fn from(resource: &str) -> Location {
+        Location::resource(resource)
+    }
+}
+
+impl From<String> for Location {
+    fn from(resource: String) -> Location {
+        Location::resource(resource)
+    }
+}
+
+/// Whether the status is a redirect which the client can follow
+pub fn is_redirect(status: u16) -> bool {
+    matches!(status, 301 | 302 | 303 | 307 | 308)
+}
+
+/// The method to repeat a request with after it is redirected with the given status
+///
+/// like HTTP, 303 turns every method but `HEAD` into `GET`, 301 and 302 turn `POST` into `GET`, and 307 and 308
+/// keep the method (and body) as they are
+pub fn redirect_method(status: u16, method: &str) -> &str {
+    match status {
+        303 if method != "HEAD" => "GET",
+        301 | 302 if method == "POST" => "GET",
+        _ => method,
+    }
+}
+
+impl JsontpResponse {
+    /// Where the response redirects to, if it has a well formed `location` header
+    pub fn location(&self) -> Option<Location> {
+        match self.headers.get("location")? {
+            Value::String(resource) => Some(Location::resource(resource)),
+            Value::Object(location) => Some(Location {
+                resource: location.get("resource")?.as_str()?.to_string(),
+                host: location.get("host").and_then(Value::as_str).map(str::to_string),
+                port: location
+                    .get("port")
+                    .and_then(Value::as_u64)
+                    .and_then(|port| u16::try_from(port).ok()),
+            }),
+            _ => None,
+        }
+    }
+}
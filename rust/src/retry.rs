@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::method::Method;
+use crate::shared::JsontpResponse;
+
+/// When and how often a client retries a request that failed
+///
+/// a request is retried if it could not be sent or answered, e.g. because connecting failed or timed out, or if it
+/// was answered with one of the retryable statuses (429, 502, 503 and 504 by default). by default, only requests
+/// with idempotent methods are retried, since others may have taken effect even though they failed
+///
+/// retries back off exponentially, waiting `base_delay`, then twice that, and so on up to `max_delay`, with random
+/// jitter so that many clients do not all retry at once. a `retry-after` header in the response overrides the
+/// backoff, and if it asks for longer than `max_delay` the response is returned instead of retrying
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub(crate) max_attempts: u32,
+    pub(crate) base_delay: Duration,
+    pub(crate) max_delay: Duration,
+    pub(crate) jitter: bool,
+    pub(crate) statuses: Vec<u16>,
+    pub(crate) any_method: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new()
+    }
+}
+
+impl RetryPolicy {
+    /// Create a new policy, making up to 3 attempts with backoff starting at 100ms and capped at 10s
+    pub fn new() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            jitter: true,
+            statuses: vec![429, 502, 503, 504],
+            any_method: false,
+        }
+    }
+
+    /// Set how many attempts to make in total, including the first
+    pub fn max_attempts(mut self, max_attempts: u32) -> RetryPolicy {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Set the delay before the first retry, and the most to wait before any retry
+    pub fn backoff(mut self, base_delay: Duration, max_delay: Duration) -> RetryPolicy {
+        self.base_delay = base_delay;
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Set whether delays are randomised, which is the default
+    pub fn jitter(mut self, jitter: bool) -> RetryPolicy {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Set the response statuses which are retried
+    pub fn statuses(mut self, statuses: &[u16]) -> RetryPolicy {
+        self.statuses = statuses.to_vec();
+        self
+    }
+
+    /// Also retry requests with methods that are not idempotent, such as `POST`
+    pub fn any_method(mut self, any_method: bool) -> RetryPolicy {
+        self.any_method = any_method;
+        self
+    }
+
+    pub(crate) fn allows(&self, method: &str) -> bool {
+        self.any_method || Method::from(method).is_idempotent()
+    }
+
+    /// how long to wait before the given retry, counting from 1, or `None` if the response asks for too long a wait
+    pub(crate) fn delay(&self, retry: u32, response: Option<&JsontpResponse>) -> Option<Duration> {
+        if let Some(retry_after) = response.and_then(retry_after) {
+            return (retry_after <= self.max_delay).then_some(retry_after);
+        }
+
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry - 1))
+            .min(self.max_delay);
+
+        if !self.jitter {
+            return Some(delay);
+        }
+
+        // "equal jitter": at least half the delay, plus a random part of the rest
+        let mut random = [0u8; 4];
+        let _ = getrandom::getrandom(&mut random);
+
+        let fraction = u32::from_le_bytes(random) as f64 / u32::MAX as f64;
+
+        Some(delay / 2 + (delay / 2).mul_f64(fraction))
+    }
+}
+
+/// the delay asked for by the `retry-after` header of a response, in seconds
+fn retry_after(response: &JsontpResponse) -> Option<Duration> {
+    let seconds = match response.headers.get("retry-after")? {
+        Value::Number(number) => number.as_f64()?,
+        Value::String(s) => s.trim().parse().ok()?,
+        _ => return None,
+    };
+
+    Duration::try_from_secs_f64(seconds).ok()
+}
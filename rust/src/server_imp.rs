@@ -5,7 +5,23 @@ use std::collections::HashMap;
 
 use serde_json::{Value, self};
 
-use std::io::{Read, Write};
+
+use std::net::SocketAddr;
+
+use crate::transport::*;
+use crate::middleware::Middleware;
+use crate::access_log::{AccessLog, AccessLogEntry};
+use crate::metrics::*;
+use crate::cookie::Cookie;
+use crate::signing::Keyring;
+use crate::redirect::Location;
+use crate::extract::{Handler, StateMap};
+use crate::method::Method;
+use crate::encoding::EncodingRegistry;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 
 impl Default for Language {
@@ -17,14 +33,14 @@ impl Default for Language {
     }
 }
 
-impl ToString for Language {
-    fn to_string(&self) -> String {
+impl core::fmt::Display for Language {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self.lang.clone() {
             Some(lang) => match self.locale.clone() {
-                Some(locale) => format!("{}-{}", lang, locale),
-                None => format!("{}-{}", lang, lang.to_ascii_uppercase()),
+                Some(locale) => write!(f, "{}-{}", lang, locale),
+                None => write!(f, "{}-{}", lang, lang.to_ascii_uppercase()),
             },
-            None => "en-US".to_string(),
+            None => write!(f, "en-US"),
         }
     }
 }
@@ -38,6 +54,15 @@ impl Response {
         language: Language,
         headers: Option<HashMap<String, Value>>,
     ) -> Response {
+        let mut cookies: Vec<Cookie> = cookies
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, value)| Cookie::new(name, value))
+            .collect();
+
+        // keep the set-cookie header in a stable order
+        cookies.sort_by(|a, b| a.name.cmp(&b.name));
+
         Response {
             body,
             status,
@@ -48,8 +73,99 @@ impl Response {
         }
     }
 
+    /// start building a response with the given status code, see [`ResponseBuilder`]
+    pub fn builder(status: u16) -> ResponseBuilder {
+        ResponseBuilder {
+            response: Response::new_manual(Body::new("", "identity", None), status, None, String::new(), Language::default(), None),
+        }
+    }
+
+    /// a 200 response with the given body
+    pub fn ok<T: ToString>(content: T) -> Response {
+        Response::builder(200).body(content).build()
+    }
+
+    /// a 200 response whose body is the given value as JSON, with a `content-type` of `application/json`
+    ///
+    /// a value which cannot be serialized, such as a map with non-string keys, gives a 500 response instead
+    pub fn json<T: serde::Serialize + ?Sized>(value: &T) -> Response {
+        match serde_json::to_string(value) {
+            Ok(content) => Response::builder(200)
+                .body(content)
+                .header("content-type", "application/json".into())
+                .build(),
+            Err(e) => Response::builder(500).body(format!("Error serializing response: {}", e)).build(),
+        }
+    }
+
+    /// a redirect to the given location, which is 308 if it is permanent and 307 otherwise
+    ///
+    /// both keep the method of the request when followed; use [`Response::builder`] for the other redirect statuses
+    pub fn redirect<L: Into<Location>>(location: L, permanent: bool) -> Response {
+        let location = location.into();
+
+        Response::builder(if permanent { 308 } else { 307 })
+            .body(format!("Redirecting to {}", location.resource))
+            .header("location", location.to_value())
+            .build()
+    }
+
+    /// a 404 response
+    pub fn not_found() -> Response {
+        Response::builder(404).body("Resource not found").build()
+    }
+
+    /// a 204 response, with an empty body
+    pub fn no_content() -> Response {
+        Response::builder(204).build()
+    }
+
+    /// the status code of the response
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// change the status code of the response
+    pub fn set_status(&mut self, status: u16) {
+        self.status = status;
+    }
+
+    /// the body of the response
+    pub fn body(&self) -> &Body {
+        &self.body
+    }
+
+    /// change the body of the response
+    pub fn body_mut(&mut self) -> &mut Body {
+        &mut self.body
+    }
+
+    /// set a cookie on the client, replacing any cookie already set by this response with the same name
+    pub fn set_cookie(&mut self, cookie: Cookie) {
+        self.cookies.retain(|existing| existing.name != cookie.name);
+        self.cookies.push(cookie);
+    }
+
+    /// the cookies set by the response
+    pub fn cookies(&self) -> &[Cookie] {
+        &self.cookies
+    }
+
+    /// get a header of the response
+    pub fn get_header(&self, key: &str) -> Option<&Value> {
+        self.headers.as_ref().and_then(|headers| headers.get(key))
+    }
+
+    /// set a header of the response
+    pub fn set_header<T: ToString>(&mut self, key: T, value: Value) {
+        self.headers
+            .get_or_insert_with(HashMap::new)
+            .insert(key.to_string(), value);
+    }
+
     pub(crate) fn validate(&self) -> Result<(), String> {
-        if self.body.content.is_empty() {
+        // only responses which say they have no content may leave the body empty
+        if self.body.content.is_empty() && !matches!(self.status, 204 | 304) {
             return Err("Body is empty".to_string());
         }
 
@@ -57,12 +173,6 @@ impl Response {
             return Err("Status code is not in the range 100-599".to_string());
         }
 
-        let allowed_encodings = vec!["gzip", "deflate", "br", "identity"];
-
-        if !allowed_encodings.contains(&self.body.encoding.as_str()) {
-            return Err("Body encoding is not allowed".to_string());
-        }
-
         Ok(())
     }
 
@@ -78,23 +188,21 @@ impl Response {
             },
         };
 
-        let mut headers: HashMap<String, Value> = self.headers.clone().unwrap_or(HashMap::new());
+        let mut headers: HashMap<String, Value> = self.headers.clone().unwrap_or_default();
 
         // date must be in the format %Y-%m-%dT%H:%M:%SZ%z, using chrono crate
         let now = chrono::Utc::now();
 
-        let formatted = now.format("%Y-%m-%dT%H:%M:%SZ%z").to_string();
+        let formatted = now.format(DATE_FORMAT).to_string();
 
         headers.insert("date".to_string(), Value::String(formatted));
 
         // now insert language type, by default it is en-US
         headers.insert("language".to_string(), Value::String(self.language.to_string()));
 
-        // now check cookies
-        if let Some(cookies) = self.cookies.clone() {
-            for (key, value) in cookies {
-                headers.insert(key, Value::String(value));
-            }
+        // now add cookies, as described on `Cookie`
+        if !self.cookies.is_empty() {
+            headers.insert("set-cookie".to_string(), serde_json::to_value(&self.cookies).unwrap());
         }
 
         JsontpResponse {
@@ -102,20 +210,110 @@ impl Response {
             type_of_response: "response".to_string(),
             status,
             resource: self.resource.clone(),
-            headers: HashMap::new(),
+            headers,
             body: self.body.clone(),
+            encoded_body: None,
         }
     }
 }
 
+/// A fluent way to build a [`Response`], started with [`Response::builder`]
+///
+/// the body is empty and uses the `identity` encoding until set, and the language is `en-US`. the resource is
+/// filled in with the one the request was for
+pub struct ResponseBuilder {
+    response: Response,
+}
+
+impl ResponseBuilder {
+    /// Set the content of the body
+    pub fn body<T: ToString>(mut self, content: T) -> ResponseBuilder {
+        self.response.body.set_text(content.to_string());
+        self
+    }
+
+    /// Set the body to the given bytes, see [`Body::from_bytes`]
+    pub fn bytes(mut self, bytes: &[u8]) -> ResponseBuilder {
+        let body = Body::from_bytes(bytes);
+
+        self.response.body.content = body.content;
+        self.response.body.encoding = body.encoding;
+        self.response.body.other.extend(body.other);
+        self
+    }
+
+    /// Set the encoding of the body
+    pub fn encoding<T: ToString>(mut self, encoding: T) -> ResponseBuilder {
+        self.response.body.encoding = encoding.to_string();
+        self
+    }
+
+    /// Set a key in the body, next to its content and encoding
+    pub fn body_key<T: ToString>(mut self, key: T, value: Value) -> ResponseBuilder {
+        self.response.body.other.insert(key.to_string(), value);
+        self
+    }
+
+    /// Set a header
+    pub fn header<T: ToString>(mut self, key: T, value: Value) -> ResponseBuilder {
+        self.response.set_header(key, value);
+        self
+    }
+
+    /// Set a cookie on the client
+    pub fn cookie(mut self, cookie: Cookie) -> ResponseBuilder {
+        self.response.set_cookie(cookie);
+        self
+    }
+
+    /// Set the language of the response
+    pub fn language(mut self, language: Language) -> ResponseBuilder {
+        self.response.language = language;
+        self
+    }
+
+    /// Finish building the response
+    pub fn build(self) -> Response {
+        self.response
+    }
+}
+
+/// A route handler with its argument types erased, as stored by the server
+pub type BoxedHandler = Arc<dyn Fn(JsontpRequest) -> Response + Send + Sync>;
+
 #[derive(Clone)]
 pub struct Server {
     pub name: String,
     pub host: String,
     pub version: String,
     pub port: u16,
-    pub route_handlers: HashMap<String, fn(JsontpRequest) -> Response>,
+    /// whether the server listens on its own host and port, which is not the case for servers made with [`Server::new_unix`]
+    pub listen_tcp: bool,
+    pub additional_addresses: Vec<String>,
+    #[cfg(unix)]
+    pub unix_paths: Vec<std::path::PathBuf>,
+    /// custom transports to accept connections from, in addition to the sockets above
+    pub transports: Vec<Arc<dyn Transport>>,
+    #[cfg(feature = "tls")]
+    pub tls: Option<crate::tls::ServerTlsConfig>,
+    pub route_handlers: HashMap<String, BoxedHandler>,
     pub error_handlers: HashMap<u16, fn(JsontpRequest) -> Response>,
+    pub middleware: Vec<Arc<dyn Middleware>>,
+    pub access_log: Option<AccessLog>,
+    pub metrics: Option<Arc<dyn Metrics>>,
+    pub route_middleware: HashMap<String, Vec<Arc<dyn Middleware>>>,
+    /// the keys responses are signed with, if any
+    pub signing: Option<Keyring>,
+    pub(crate) state: Arc<StateMap>,
+    /// methods accepted in addition to the standard ones
+    pub extension_methods: Vec<Method>,
+    /// the methods each route accepts, for routes which do not accept every method
+    pub route_methods: HashMap<String, Vec<Method>>,
+    pub encodings: EncodingRegistry,
+    /// how long a connection may take to send its whole request, so slow or idle clients cannot hold on to a worker
+    pub read_timeout: Option<Duration>,
+    /// the largest request the server reads, in bytes
+    pub max_message_size: usize,
 }
 
 impl Server {
@@ -127,14 +325,145 @@ impl Server {
             host: host.to_string(),
             version: "1.0-rc1".to_string(),
             port,
+            listen_tcp: true,
+            additional_addresses: Vec::new(),
+            #[cfg(unix)]
+            unix_paths: Vec::new(),
+            transports: Vec::new(),
+            #[cfg(feature = "tls")]
+            tls: None,
             route_handlers: HashMap::new(),
             error_handlers: HashMap::new(),
+            middleware: Vec::new(),
+            access_log: None,
+            metrics: None,
+            route_middleware: HashMap::new(),
+            signing: None,
+            state: Arc::new(StateMap::default()),
+            extension_methods: Vec::new(),
+            route_methods: HashMap::new(),
+            encodings: EncodingRegistry::new(),
+            read_timeout: Some(DEFAULT_READ_TIMEOUT),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
         }
     }
 
+    /// instantiates a new server, with given name, which only listens on the unix domain socket at the given path
+    #[cfg(unix)]
+    pub fn new_unix<T, P>(name: T, path: P) -> Server
+    where T: ToString, P: AsRef<std::path::Path> {
+        let mut server = Server::new(name, "", 0);
+
+        server.listen_tcp = false;
+        server.listen_unix(path);
+
+        server
+    }
+
     /// adds a route to the server, with the given handler
-    pub fn route<T: ToString>(&mut self, route: T, handler: fn(JsontpRequest) -> Response) {
-        self.route_handlers.insert(route.to_string(), handler);
+    ///
+    /// segments of the route like `{id}` match any single segment of a resource, and can be read by the handler
+    /// with [`crate::extract::Path`]. handlers take any number of arguments extracted from the request, see [`Handler`]
+    pub fn route<T: ToString, H: Handler<Args>, Args: 'static>(&mut self, route: T, handler: H) {
+        self.route_handlers
+            .insert(route.to_string(), Arc::new(move |request| handler.call(request)));
+    }
+
+    /// only accepts the given methods for the route, answering others with a 405
+    ///
+    /// `HEAD` is accepted along with `GET`, and `OPTIONS` is answered automatically unless it is in the list
+    pub fn route_methods<T: ToString>(&mut self, route: T, methods: &[Method]) {
+        self.route_methods.insert(route.to_string(), methods.to_vec());
+    }
+
+    /// also accepts the given extension method, e.g. `"PURGE"`; requests with unknown methods are answered with a 501
+    pub fn extension_method<M: Into<Method>>(&mut self, method: M) {
+        self.extension_methods.push(method.into());
+    }
+
+    /// accepts request bodies in the given encodings, and encodes responses with the first of its codecs that the
+    /// request lists in its `accept-encoding` header
+    ///
+    /// request bodies in a codec's encoding are decoded before they reach the route handler, but after middleware
+    /// has seen them, so signatures are checked against what was sent. requests in any other encoding are answered
+    /// with a 400
+    pub fn encodings(&mut self, encodings: EncodingRegistry) {
+        self.encodings = encodings;
+    }
+
+    /// sets how long a connection may take to send its whole request, including any TLS handshake (30 seconds by
+    /// default), or lets it take forever if `None`
+    ///
+    /// shutting the server down waits for requests being read to time out, so this also bounds how long that takes
+    pub fn read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// sets the largest request the server reads, in bytes (16 MiB by default); connections sending larger ones are
+    /// closed without a response
+    pub fn max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    /// the methods the route accepts, as sent in the `allow` header
+    fn allowed_methods(&self, route: &str) -> Vec<Method> {
+        let mut allowed = match self.route_methods.get(route) {
+            Some(methods) => methods.clone(),
+            None => Method::STANDARD.iter().chain(&self.extension_methods).cloned().collect(),
+        };
+
+        for implied in [Method::Head, Method::Options] {
+            if !allowed.contains(&implied) && (implied != Method::Head || allowed.contains(&Method::Get)) {
+                allowed.push(implied);
+            }
+        }
+
+        allowed
+    }
+
+    /// gives handlers shared access to the given value, with the [`crate::extract::State`] extractor
+    ///
+    /// there is one value per type, so giving another of the same type replaces it
+    pub fn state<S: Send + Sync + 'static>(&mut self, state: S) {
+        Arc::make_mut(&mut self.state).insert(state);
+    }
+
+    /// the route matching the resource, and the parameters it matched
+    ///
+    /// routes without parameters take precedence, then those with the most literal segments
+    fn find_route(&self, resource: &str) -> Option<(&String, Vec<(String, String)>)> {
+        let path = resource.split_once('?').map_or(resource, |(path, _)| path);
+
+        if let Some((route, _)) = self.route_handlers.get_key_value(path) {
+            return Some((route, Vec::new()));
+        }
+
+        let segments: Vec<&str> = path.split('/').collect();
+
+        self.route_handlers
+            .keys()
+            .filter(|route| route.contains('{'))
+            .filter_map(|route| {
+                let pattern: Vec<&str> = route.split('/').collect();
+
+                if pattern.len() != segments.len() {
+                    return None;
+                }
+
+                let mut params = Vec::new();
+
+                for (pattern, segment) in pattern.iter().zip(&segments) {
+                    match pattern.strip_prefix('{').and_then(|name| name.strip_suffix('}')) {
+                        Some(name) if !segment.is_empty() => params.push((name.to_string(), segment.to_string())),
+                        Some(_) => return None,
+                        None if pattern == segment => {}
+                        None => return None,
+                    }
+                }
+
+                Some((route, params))
+            })
+            .max_by(|(a, a_params), (b, b_params)| b_params.len().cmp(&a_params.len()).then_with(|| b.cmp(a)))
     }
 
     /// adds an error handler to the server, with the given code
@@ -142,69 +471,567 @@ impl Server {
         self.error_handlers.insert(code, handler);
     }
 
-    /// starts the server on the given host and port
-    pub fn start(self) {
-        let listener = std::net::TcpListener::bind(format!("{}:{}", self.host, self.port)).unwrap();
+    /// adds middleware which runs around every request
+    pub fn middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middleware.push(Arc::new(middleware));
+    }
+
+    /// adds middleware which only runs around requests to the given route
+    pub fn route_middleware<T: ToString, M: Middleware + 'static>(&mut self, route: T, middleware: M) {
+        self.route_middleware
+            .entry(route.to_string())
+            .or_default()
+            .push(Arc::new(middleware));
+    }
+
+    /// writes a line to the given access log for every request handled
+    pub fn access_log(&mut self, access_log: AccessLog) {
+        self.access_log = Some(access_log);
+    }
+
+    /// records metrics about every connection and request handled
+    pub fn metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// serves the given metrics in the Prometheus text format at the given resource
+    pub fn metrics_route<T: ToString>(&mut self, resource: T, metrics: Arc<InMemoryMetrics>) {
+        self.middleware(MetricsEndpoint::new(resource, metrics));
+    }
+
+    /// signs every response with the first key of the given keyring, see [`Keyring`]
+    pub fn sign_responses(&mut self, keyring: Keyring) {
+        self.signing = Some(keyring);
+    }
+
+    /// also listens on the given address (e.g. `"[::1]:0"`), in addition to the server's own host and port
+    pub fn listen_on<T: ToString>(&mut self, address: T) {
+        self.additional_addresses.push(address.to_string());
+    }
+
+    /// also listens on the unix domain socket at the given path, which must not exist yet
+    ///
+    /// the socket file is removed again when the server shuts down
+    #[cfg(unix)]
+    pub fn listen_unix<P: AsRef<std::path::Path>>(&mut self, path: P) {
+        self.unix_paths.push(path.as_ref().to_path_buf());
+    }
+
+    /// also accepts connections from the given transport, such as a [`MemoryTransport`]
+    pub fn listen_with<T: Transport + 'static>(&mut self, transport: T) {
+        self.transports.push(Arc::new(transport));
+    }
+
+    /// serves every connection over TLS, using the given certificate configuration
+    #[cfg(feature = "tls")]
+    pub fn tls(&mut self, config: crate::tls::ServerTlsConfig) {
+        self.tls = Some(config);
+    }
+
+    /// starts the server on the given host and port, accepting connections on a background thread
+    ///
+    /// binding to port 0 picks a free port, which can be read back with [`ServerHandle::local_addr`]. an error is
+    /// returned if any of the addresses cannot be bound, in which case nothing is left listening
+    ///
+    /// the returned [`ServerHandle`] is used to stop the server again; dropping it leaves the server running
+    pub fn start(mut self) -> std::io::Result<ServerHandle> {
+        let mut listeners: Vec<Arc<dyn Transport>> = Vec::new();
+
+        if self.listen_tcp {
+            listeners.push(Arc::new(bind_tcp(format!("{}:{}", self.host, self.port))?));
+        }
+
+        for address in &self.additional_addresses {
+            listeners.push(Arc::new(bind_tcp(address.as_str())?));
+        }
+
+        #[cfg(unix)]
+        for path in &self.unix_paths {
+            listeners.push(Arc::new(UnixTransport::bind(path)?));
+        }
+
+        listeners.append(&mut self.transports);
+
+        let local_addrs = listeners
+            .iter()
+            .map(|listener| listener.local_addr())
+            .collect::<std::io::Result<Vec<_>>>()?;
 
-        for stream in listener.incoming() {
+        let stopping = Arc::new(AtomicBool::new(false));
+        let workers: Arc<Mutex<Vec<JoinHandle<()>>>> = Arc::new(Mutex::new(Vec::new()));
+
+        let acceptors = listeners
+            .into_iter()
+            .map(|listener| {
+                let server = self.clone();
+                let stopping = stopping.clone();
+                let workers = workers.clone();
+
+                std::thread::spawn(move || server.accept_loop(listener, stopping, workers))
+            })
+            .collect();
+
+        Ok(ServerHandle {
+            stopping,
+            acceptors,
+            workers,
+            local_addrs,
+        })
+    }
+
+    fn accept_loop(self, listener: Arc<dyn Transport>, stopping: Arc<AtomicBool>, workers: Arc<Mutex<Vec<JoinHandle<()>>>>) {
+        while !stopping.load(Ordering::SeqCst) {
+            let stream = match listener.accept() {
+                Ok(stream) => stream,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => {
+                    trace_warn!("failed to accept connection: {}", e);
+
+                    // errors such as running out of file descriptors tend to persist, so don't spin on them
+                    std::thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue;
+                }
+            };
 
             let server = self.clone();
 
-            std::thread::spawn(move || {
-                if let Err(e) = stream {
-                    eprintln!("failed: {}", e);
-                    return;
+            let worker = std::thread::spawn(move || server.serve(stream));
+
+            let mut workers = workers.lock().unwrap();
+
+            workers.retain(|worker| !worker.is_finished());
+            workers.push(worker);
+        }
+    }
+
+    fn serve(&self, stream: Box<dyn Stream>) {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            match tls.accept(stream) {
+                Ok(stream) => {
+                    let mut connection = self.framed(stream);
+
+                    self.serve_connection(&mut connection);
+
+                    let stream = connection.get_mut();
+
+                    stream.conn.send_close_notify();
+                    let _ = std::io::Write::flush(stream);
                 }
+                Err(e) => trace_warn!("failed to start TLS session: {}", e),
+            }
+
+            return;
+        }
+
+        self.serve_connection(&mut self.framed(stream));
+    }
+
+    fn framed<S: Stream>(&self, stream: S) -> Framed<S> {
+        Framed::new(stream)
+            .max_message_size(self.max_message_size)
+            .read_timeout(self.read_timeout)
+    }
+
+    /// reads a single request from the connection, and writes the response back
+    ///
+    /// this is what the server does for every connection it accepts, and can be used to drive the server over a
+    /// connection of your own, such as one end of a [`pipe`]
+    pub fn serve_connection<C: Connection + ?Sized>(&self, connection: &mut C) {
+        let peer = connection.peer();
+
+        #[cfg(feature = "tracing")]
+        let _connection_span = tracing::info_span!("connection", %peer).entered();
+
+        let _open = self.metrics.as_ref().map(|metrics| OpenConnection::new(metrics.as_ref()));
+
+        let message = match connection.read_message() {
+            Ok(message) => message,
+            Err(e) => {
+                trace_warn!("failed to read request from {}: {}", peer, e);
+                return;
+            }
+        };
 
-                println!("Handling connection from {}", stream.as_ref().unwrap().peer_addr().unwrap());
+        let timestamp = chrono::Utc::now();
+        let started = Instant::now();
 
-                let mut stream = stream.unwrap();
+        #[cfg(feature = "tracing")]
+        let request_span = tracing::info_span!(
+            "request",
+            method = tracing::field::Empty,
+            resource = tracing::field::Empty,
+            status = tracing::field::Empty,
+        )
+        .entered();
 
+        // a request which is not valid UTF-8 is rejected rather than repaired, as that would corrupt it
+        let parsed = std::str::from_utf8(&message)
+            .map_err(|e| format!("request is not valid UTF-8: {}", e))
+            .and_then(|request| serde_json::from_str::<JsontpRequest>(request).map_err(|e| e.to_string()));
 
-                let mut request_string = String::new();
+        // the request is consumed by its handler, so keep what the access log and metrics need
+        let (response, jsontp, method, resource, encoding, labels) = match parsed {
+            Ok(mut request) => {
+                request.peer = Some(peer.clone());
 
-                let mut buf_reader = std::io::BufReader::new(&stream);
+                #[cfg(feature = "tracing")]
+                request_span
+                    .record("method", request.method.as_str())
+                    .record("resource", request.resource.as_str());
 
-                loop {
-                    let mut buffer = [0; 1024];
-                    let bytes_read = buf_reader.read(&mut buffer).unwrap();
+                let summary = (
+                    request.jsontp.clone(),
+                    request.method.clone(),
+                    request.resource.clone(),
+                    request.body.encoding.clone(),
+                    self.metric_labels(&request),
+                );
 
-                    request_string.push_str(&String::from_utf8_lossy(&buffer[..bytes_read]));
+                (self.dispatch(request), summary.0, summary.1, summary.2, summary.3, summary.4)
+            }
+            Err(e) => {
+                trace_warn!("failed to parse request from {}: {}", peer, e);
 
-                    if bytes_read < 1024 {
-                        break;
-                    }   
+                if let Some(metrics) = &self.metrics {
+                    metrics.parse_error(Side::Server);
                 }
 
-                let request: JsontpRequest = serde_json::from_str(&request_string).unwrap();
-
-                let response = match server.route_handlers.get(&request.resource) {
-                    Some(handler) => handler(request).to_jsontp_response(),
-                    None => JsontpResponse {
-                        jsontp: "1.0-rc1".to_string(),
-                        type_of_response: "response".to_string(),
-                        status: Status {
-                            code: 404,
-                            formal_message: "Not Found".to_string(),
-                            human_message: "Resource not found".to_string(),
-                        },
-                        resource: request.resource.clone(),
-                        headers: HashMap::new(),
-                        body: Body {
-                            content: "".to_string(),
-                            encoding: "".to_string(),
-                            other: HashMap::new(),
+                let response = Response::new_manual(
+                    Body::new(format!("Invalid request: {}", e), "identity", None),
+                    400,
+                    None,
+                    "".to_string(),
+                    Language::default(),
+                    None,
+                );
+
+                let unknown = || "-".to_string();
+
+                (response.to_jsontp_response(), unknown(), unknown(), unknown(), unknown(), (unknown(), unknown(), unknown()))
+            }
+        };
+
+        let response_string = serde_json::to_string(&response).unwrap();
+
+        if let Err(e) = connection.write_message(response_string.as_bytes()) {
+            trace_warn!("failed to write response to {}: {}", peer, e);
+            return;
+        }
+
+        let duration = started.elapsed();
+
+        if let Some(metrics) = &self.metrics {
+            metrics.request_completed(&RequestMetrics {
+                side: Side::Server,
+                method: &labels.0,
+                resource: &labels.1,
+                status: response.status.code,
+                encoding: &labels.2,
+                bytes_received: message.len(),
+                bytes_sent: response_string.len(),
+                duration,
+            });
+        }
+
+        #[cfg(feature = "tracing")]
+        {
+            request_span.record("status", response.status.code);
+
+            tracing::info!(
+                bytes_in = message.len(),
+                bytes_out = response_string.len(),
+                latency_us = duration.as_micros() as u64,
+                "handled request",
+            );
+        }
+
+        if let Some(access_log) = &self.access_log {
+            access_log.record(&AccessLogEntry {
+                timestamp,
+                peer,
+                jsontp,
+                method,
+                resource,
+                status: response.status.code,
+                encoding,
+                request_bytes: message.len(),
+                response_bytes: response_string.len(),
+                duration,
+            });
+        }
+    }
+
+    /// the method, resource and encoding to record metrics under: the route the request matched rather than the
+    /// resource it asked for, and only methods and encodings the server accepts, so that clients cannot make up new
+    /// labels without end
+    fn metric_labels(&self, request: &JsontpRequest) -> (String, String, String) {
+        let method = request.method();
+
+        let method = if Method::STANDARD.contains(&method) || self.extension_methods.contains(&method) {
+            method.to_string()
+        } else {
+            "OTHER".to_string()
+        };
+
+        let route = match self.find_route(&request.resource) {
+            Some((route, _)) => route.clone(),
+            None => "unmatched".to_string(),
+        };
+
+        let encoding = if self.encodings.contains(&request.body.encoding) {
+            request.body.encoding.clone()
+        } else {
+            "other".to_string()
+        };
+
+        (method, route, encoding)
+    }
+
+    /// routes a parsed request to its handler, producing the response to send back
+    pub(crate) fn dispatch(&self, request: JsontpRequest) -> JsontpResponse {
+        let resource = request.resource.clone();
+        let head = request.method() == Method::Head;
+        let accept_encoding = request.headers.get("accept-encoding").cloned();
+
+        let mut response = self.handle(request);
+
+        // HEAD requests are handled like GET, but only get the headers back
+        if head {
+            response.body.content.clear();
+        }
+
+        if !self.encodings.contains(&response.body.encoding) {
+            response.status = Status {
+                code: 400,
+                formal_message: "Bad Request".to_string(),
+                human_message: "Body encoding is not allowed".to_string(),
+            };
+        }
+
+        let codec = accept_encoding.as_ref().and_then(|accept| self.encodings.negotiate(accept));
+
+        if let Some(codec) = codec {
+            if response.body.encoding == "identity" && codec.name() != "identity" && !response.body.content.is_empty() {
+                if let Err(e) = self.encodings.encode(&mut response.body, codec.name()) {
+                    trace_warn!("failed to encode response as {}: {}", codec.name(), e);
+                }
+            }
+        }
+
+        // responses built without the request, e.g. with `Response::ok`, are for whatever it asked for
+        if response.resource.is_empty() {
+            response.resource = resource;
+        }
+
+        if let Some(keyring) = &self.signing {
+            if let Err(e) = keyring.sign_response(&mut response) {
+                trace_warn!("failed to sign response: {}", e);
+            }
+        }
+
+        response
+    }
+
+    fn handle(&self, mut request: JsontpRequest) -> JsontpResponse {
+        let mut entered: Vec<&Arc<dyn Middleware>> = Vec::new();
+
+        let mut response = run_before(&self.middleware, &mut request, &mut entered);
+
+        let method = request.method();
+
+        if response.is_none() && !Method::is_valid(method.as_str()) {
+            response = Some(Response::builder(400).body(format!("Method {} is not allowed", method)).build());
+        } else if response.is_none() && !Method::STANDARD.contains(&method) && !self.extension_methods.contains(&method) {
+            response = Some(Response::builder(501).body(format!("Method {} is not supported", method)).build());
+        }
+
+        if response.is_none() && !self.encodings.contains(&request.body.encoding) {
+            response = Some(Response::builder(400).body(format!("Encoding {} is not allowed", request.body.encoding)).build());
+        }
+
+        if response.is_none() {
+            response = match self.find_route(&request.resource) {
+                Some((route, _)) if !self.allowed_methods(route).contains(&method) => Some(
+                    Response::builder(405)
+                        .body(format!("Method {} is not allowed for {}", method, route))
+                        .header("allow", allow_header(&self.allowed_methods(route)))
+                        .build(),
+                ),
+                Some((route, _)) if method == Method::Options && !self.route_methods.get(route).is_some_and(|methods| methods.contains(&Method::Options)) => {
+                    Some(Response::builder(204).header("allow", allow_header(&self.allowed_methods(route))).build())
+                }
+                Some((route, params)) => {
+                    let handler = &self.route_handlers[route];
+                    let route_middleware = self.route_middleware.get(route).map(Vec::as_slice).unwrap_or_default();
+
+                    request.params = params;
+                    request.state = Some(self.state.clone());
+
+                    match run_before(route_middleware, &mut request, &mut entered) {
+                        Some(response) => Some(response),
+                        None => match self.encodings.decode(&mut request.body) {
+                            Err(e) => Some(Response::builder(400).body(e).build()),
+                            // the request is only kept around if there are `after` hooks that need it
+                            Ok(()) if entered.is_empty() => return handler(request).to_jsontp_response(),
+                            Ok(()) => Some(handler(request.clone())),
                         },
-                    },
-                };
+                    }
+                }
+                None => Some(Response::not_found()),
+            };
+        }
+
+        let mut response = response.unwrap();
+
+        for middleware in entered.iter().rev() {
+            middleware.after(&request, &mut response);
+        }
+
+        response.to_jsontp_response()
+    }
+}
+
+/// the `allow` header listing the given methods
+fn allow_header(methods: &[Method]) -> Value {
+    Value::Array(methods.iter().map(|method| Value::String(method.to_string())).collect())
+}
+
+/// counts a connection as in flight for as long as it is alive
+struct OpenConnection<'a> {
+    metrics: &'a dyn Metrics,
+}
+
+impl<'a> OpenConnection<'a> {
+    fn new(metrics: &'a dyn Metrics) -> OpenConnection<'a> {
+        metrics.connection_opened();
+
+        OpenConnection { metrics }
+    }
+}
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        self.metrics.connection_closed();
+    }
+}
+
+/// runs the `before` hooks of `chain`, recording each middleware that was run, until one returns a response
+fn run_before<'a>(chain: &'a [Arc<dyn Middleware>], request: &mut JsontpRequest, entered: &mut Vec<&'a Arc<dyn Middleware>>) -> Option<Response> {
+    for middleware in chain {
+        entered.push(middleware);
+
+        if let Some(response) = middleware.before(request) {
+            return Some(response);
+        }
+    }
+
+    None
+}
+
+/// how long a connection may take to send its request, unless set with [`Server::read_timeout`]
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
 
-                let response_string = serde_json::to_string(&response).unwrap();
+/// how long the accept loop sleeps when there are no pending connections
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
-                stream.write(response_string.as_bytes()).unwrap();
+/// a handle to a running server, returned by [`Server::start`]
+pub struct ServerHandle {
+    stopping: Arc<AtomicBool>,
+    acceptors: Vec<JoinHandle<()>>,
+    workers: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    local_addrs: Vec<Address>,
+}
+
+impl ServerHandle {
+    /// the first TCP address the server is listening on, with the real port if port 0 was requested
+    ///
+    /// this is the server's own host and port, unless it was made with [`Server::new_unix`]
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs.iter().find_map(Address::tcp)
+    }
+
+    /// every address the server is listening on: its own host and port, then additional TCP addresses, then unix sockets
+    pub fn local_addrs(&self) -> &[Address] {
+        &self.local_addrs
+    }
 
-                println!("Handled connection from {}, with response: {} {}", stream.peer_addr().unwrap(), response.status.code, response.status.formal_message);
+    /// stops accepting new connections, then waits for every in-flight request to finish
+    pub fn shutdown(mut self) {
+        self.stop_accepting();
+
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+
+        for worker in workers {
+            let _ = worker.join();
+        }
+    }
+
+    /// stops accepting new connections, then waits up to `timeout` for in-flight requests to finish
+    ///
+    /// returns `true` if every request was drained in time, and `false` if some were still running
+    pub fn shutdown_graceful(mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        self.stop_accepting();
+
+        let mut workers = std::mem::take(&mut *self.workers.lock().unwrap());
+
+        loop {
+            let (finished, running): (Vec<_>, Vec<_>) = workers.into_iter().partition(|worker| worker.is_finished());
+
+            for worker in finished {
+                let _ = worker.join();
             }
-            );
+
+            if running.is_empty() {
+                return true;
+            }
+
+            if Instant::now() >= deadline {
+                return false;
+            }
+
+            workers = running;
+
+            std::thread::sleep(ACCEPT_POLL_INTERVAL);
         }
     }
-}
\ No newline at end of file
+
+    /// waits for `signal` to complete, then shuts the server down gracefully, as [`ServerHandle::shutdown_graceful`] does
+    ///
+    /// this lets the server be tied to the lifecycle of an async application, e.g. a `tokio::sync::oneshot` receiver
+    pub async fn shutdown_on<F>(self, signal: F, timeout: Duration) -> bool
+    where F: std::future::Future<Output = ()> {
+        signal.await;
+
+        tokio::task::spawn_blocking(move || self.shutdown_graceful(timeout))
+            .await
+            .unwrap_or(false)
+    }
+
+    /// waits for ctrl-c (`SIGINT`), then shuts the server down gracefully
+    pub async fn shutdown_on_ctrl_c(self, timeout: Duration) -> bool {
+        self.shutdown_on(async {
+            let _ = tokio::signal::ctrl_c().await;
+        }, timeout).await
+    }
+
+    /// blocks the current thread for as long as the server is accepting connections
+    pub fn wait(mut self) {
+        for acceptor in self.acceptors.drain(..) {
+            let _ = acceptor.join();
+        }
+    }
+
+    fn stop_accepting(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+
+        for acceptor in self.acceptors.drain(..) {
+            let _ = acceptor.join();
+        }
+    }
+}
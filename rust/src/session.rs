@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::cookie::{Cookie, SameSite};
+use crate::middleware::Middleware;
+use crate::shared::{hex, unhex, JsontpRequest, Response};
+
+/// the data held in a session
+pub type SessionData = HashMap<String, Value>;
+
+/// Where session data is kept between requests
+pub trait SessionStore: Send + Sync {
+    /// Load the data of the session with the given id, if it exists
+    fn load(&self, id: &str) -> Option<SessionData>;
+
+    /// Save the data of the session with the given id, creating it if it does not exist
+    fn save(&self, id: &str, data: &SessionData) -> Result<(), String>;
+
+    /// Remove the session with the given id
+    fn remove(&self, id: &str);
+}
+
+/// How long a session may go unused before the stores forget it, unless set otherwise
+pub const DEFAULT_MAX_IDLE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// how often stores look for sessions which have gone unused for too long, besides when loading them
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A session store which keeps sessions in memory, so they are lost when the server stops
+///
+/// sessions which go unused for longer than [`DEFAULT_MAX_IDLE`], or as set with
+/// [`MemorySessionStore::max_idle`], are removed
+pub struct MemorySessionStore {
+    /// each session with when it was last used
+    sessions: Mutex<HashMap<String, (SessionData, Instant)>>,
+    max_idle: Duration,
+    last_sweep: Mutex<Instant>,
+}
+
+impl Default for MemorySessionStore {
+    fn default() -> Self {
+        MemorySessionStore::new()
+    }
+}
+
+impl MemorySessionStore {
+    /// Create a new, empty store
+    pub fn new() -> MemorySessionStore {
+        MemorySessionStore {
+            sessions: Mutex::new(HashMap::new()),
+            max_idle: DEFAULT_MAX_IDLE,
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Set how long a session may go unused before it is removed
+    pub fn max_idle(mut self, max_idle: Duration) -> MemorySessionStore {
+        self.max_idle = max_idle;
+        self
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, id: &str) -> Option<SessionData> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        let (data, last_used) = sessions.get_mut(id)?;
+
+        if last_used.elapsed() > self.max_idle {
+            sessions.remove(id);
+            return None;
+        }
+
+        *last_used = Instant::now();
+
+        Some(data.clone())
+    }
+
+    fn save(&self, id: &str, data: &SessionData) -> Result<(), String> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut last_sweep = self.last_sweep.lock().unwrap();
+
+        if last_sweep.elapsed() > SWEEP_INTERVAL {
+            sessions.retain(|_, (_, last_used)| last_used.elapsed() <= self.max_idle);
+            *last_sweep = Instant::now();
+        }
+
+        sessions.insert(id.to_string(), (data.clone(), Instant::now()));
+
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+}
+
+/// A session store which keeps each session as a JSON file in a directory
+///
+/// the modification time of a file is when its session was last used. sessions which go unused for longer than
+/// [`DEFAULT_MAX_IDLE`], or as set with [`FileSessionStore::max_idle`], are removed
+pub struct FileSessionStore {
+    directory: PathBuf,
+    max_idle: Duration,
+    last_sweep: Mutex<Instant>,
+}
+
+impl FileSessionStore {
+    /// Create a new store in the given directory, creating the directory if needed
+    pub fn new<P: Into<PathBuf>>(directory: P) -> std::io::Result<FileSessionStore> {
+        let directory = directory.into();
+
+        std::fs::create_dir_all(&directory)?;
+
+        Ok(FileSessionStore {
+            directory,
+            max_idle: DEFAULT_MAX_IDLE,
+            // look for sessions left over from before on the first save
+            last_sweep: Mutex::new(Instant::now().checked_sub(SWEEP_INTERVAL).unwrap_or_else(Instant::now)),
+        })
+    }
+
+    /// Set how long a session may go unused before it is removed
+    pub fn max_idle(mut self, max_idle: Duration) -> FileSessionStore {
+        self.max_idle = max_idle;
+        self
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.directory.join(format!("{}.json", id))
+    }
+
+    fn is_stale(&self, path: &Path) -> bool {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified.elapsed().unwrap_or_default() > self.max_idle)
+    }
+
+    /// removes the sessions, and any files left from saves which failed, that have gone unused for too long
+    fn sweep(&self) {
+        let entries = match std::fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                trace_warn!("failed to look for expired sessions: {}", e);
+                return;
+            }
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let ours = path.extension().is_some_and(|extension| extension == "json" || extension == "tmp");
+
+            if ours && self.is_stale(&path) {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&self, id: &str) -> Option<SessionData> {
+        let path = self.path(id);
+
+        if self.is_stale(&path) {
+            let _ = std::fs::remove_file(path);
+            return None;
+        }
+
+        let contents = std::fs::read(&path).ok()?;
+
+        // loading the session counts as using it
+        let _ = std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+
+        serde_json::from_slice(&contents).ok()
+    }
+
+    fn save(&self, id: &str, data: &SessionData) -> Result<(), String> {
+        {
+            let mut last_sweep = self.last_sweep.lock().unwrap();
+
+            if last_sweep.elapsed() > SWEEP_INTERVAL {
+                *last_sweep = Instant::now();
+                self.sweep();
+            }
+        }
+
+        let contents = serde_json::to_vec(data).map_err(|e| format!("Error serializing session: {}", e))?;
+
+        // written to a file of its own and then renamed over the session, so a concurrent load never sees half of it
+        let mut suffix = [0u8; 8];
+        getrandom::getrandom(&mut suffix).expect("the operating system provides randomness");
+
+        let temporary = self.directory.join(format!("{}.{}.tmp", id, hex(&suffix)));
+
+        std::fs::write(&temporary, contents)
+            .and_then(|_| std::fs::rename(&temporary, self.path(id)))
+            .map_err(|e| {
+                let _ = std::fs::remove_file(&temporary);
+
+                format!("Error saving session: {}", e)
+            })
+    }
+
+    fn remove(&self, id: &str) {
+        let _ = std::fs::remove_file(self.path(id));
+    }
+}
+
+/// The session of a request, shared between the handler and the [`SessionMiddleware`] which saves it
+#[derive(Debug, Clone)]
+pub struct Session {
+    id: String,
+    data: Arc<Mutex<SessionData>>,
+    is_new: bool,
+    changed: Arc<AtomicBool>,
+    destroyed: Arc<AtomicBool>,
+}
+
+impl Session {
+    fn new(id: String, data: SessionData, is_new: bool) -> Session {
+        Session {
+            id,
+            data: Arc::new(Mutex::new(data)),
+            is_new,
+            changed: Arc::new(AtomicBool::new(false)),
+            destroyed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// The id of the session
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Whether the session was started by this request
+    pub fn is_new(&self) -> bool {
+        self.is_new
+    }
+
+    /// Get a value from the session
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.data.lock().unwrap().get(key).cloned()
+    }
+
+    /// Set a value in the session
+    pub fn insert<T: ToString>(&self, key: T, value: Value) {
+        self.data.lock().unwrap().insert(key.to_string(), value);
+        self.changed.store(true, Ordering::SeqCst);
+    }
+
+    /// Remove a value from the session, returning it
+    pub fn remove(&self, key: &str) -> Option<Value> {
+        self.changed.store(true, Ordering::SeqCst);
+        self.data.lock().unwrap().remove(key)
+    }
+
+    /// Remove the session from the store once the request is handled, and tell the client to forget its cookie
+    pub fn destroy(&self) {
+        self.destroyed.store(true, Ordering::SeqCst);
+    }
+}
+
+impl JsontpRequest {
+    /// The session of the request, if the server uses a [`SessionMiddleware`]
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+}
+
+/// Middleware which gives every request a [`Session`], identified by a signed cookie
+///
+/// a new session only gets a cookie, and is only saved, once something is stored in it
+pub struct SessionMiddleware {
+    store: Arc<dyn SessionStore>,
+    key: Vec<u8>,
+    cookie_name: String,
+    max_age: Option<i64>,
+    secure: bool,
+}
+
+impl SessionMiddleware {
+    /// Create new session middleware, keeping sessions in the given store and signing their ids with the given key
+    pub fn new<S: SessionStore + 'static>(store: S, key: &[u8]) -> SessionMiddleware {
+        SessionMiddleware {
+            store: Arc::new(store),
+            key: key.to_vec(),
+            cookie_name: "session".to_string(),
+            max_age: None,
+            secure: false,
+        }
+    }
+
+    /// Change the name of the session cookie, which is `session` by default
+    pub fn cookie_name<T: ToString>(mut self, name: T) -> SessionMiddleware {
+        self.cookie_name = name.to_string();
+        self
+    }
+
+    /// Make the session cookie expire after the given number of seconds, instead of lasting until the client forgets it
+    ///
+    /// this only applies to the cookie; how long the server keeps a session is up to its store, e.g.
+    /// [`MemorySessionStore::max_idle`]
+    pub fn max_age(mut self, seconds: i64) -> SessionMiddleware {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    /// Only send the session cookie over TLS
+    pub fn secure(mut self, secure: bool) -> SessionMiddleware {
+        self.secure = secure;
+        self
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length")
+    }
+
+    fn sign(&self, id: &str) -> String {
+        let mut mac = self.mac();
+        mac.update(id.as_bytes());
+
+        format!("{}.{}", id, hex(&mac.finalize().into_bytes()))
+    }
+
+    /// the session id in a signed cookie value, if the signature is valid
+    fn verify<'a>(&self, value: &'a str) -> Option<&'a str> {
+        let (id, signature) = value.split_once('.')?;
+
+        // ids are always hex, which also keeps them safe to use as file names
+        if id.len() != 64 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let signature = unhex(signature)?;
+
+        let mut mac = self.mac();
+        mac.update(id.as_bytes());
+        mac.verify_slice(&signature).ok()?;
+
+        Some(id)
+    }
+
+    fn cookie(&self, value: String) -> Cookie {
+        let mut cookie = Cookie::new(&self.cookie_name, value)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .secure(self.secure);
+
+        cookie.max_age = self.max_age;
+
+        cookie
+    }
+}
+
+fn new_session_id() -> String {
+    let mut bytes = [0u8; 32];
+
+    getrandom::getrandom(&mut bytes).expect("the operating system provides randomness");
+
+    hex(&bytes)
+}
+
+impl Middleware for SessionMiddleware {
+    fn before(&self, request: &mut JsontpRequest) -> Option<Response> {
+        let existing = request
+            .cookie(&self.cookie_name)
+            .and_then(|value| self.verify(&value).map(str::to_string))
+            .and_then(|id| self.store.load(&id).map(|data| (id, data)));
+
+        request.session = Some(match existing {
+            Some((id, data)) => Session::new(id, data, false),
+            None => Session::new(new_session_id(), SessionData::new(), true),
+        });
+
+        None
+    }
+
+    fn after(&self, request: &JsontpRequest, response: &mut Response) {
+        let session = match &request.session {
+            Some(session) => session,
+            None => return,
+        };
+
+        if session.destroyed.load(Ordering::SeqCst) {
+            self.store.remove(&session.id);
+
+            if !session.is_new {
+                let mut cookie = self.cookie(String::new());
+                cookie.max_age = Some(0);
+
+                response.set_cookie(cookie);
+            }
+
+            return;
+        }
+
+        if !session.changed.load(Ordering::SeqCst) {
+            return;
+        }
+
+        if let Err(e) = self.store.save(&session.id, &session.data.lock().unwrap()) {
+            trace_warn!("failed to save session: {}", e);
+            return;
+        }
+
+        if session.is_new {
+            response.set_cookie(self.cookie(self.sign(&session.id)));
+        }
+    }
+}
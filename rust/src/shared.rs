@@ -3,6 +3,9 @@ use std::collections::HashMap;
 
 use serde_json::Value;
 
+/// the format of the `date` header
+pub(crate) const DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ%z";
+
 /// The language of a jsontp request or response, containing the language and locale
 #[derive(Debug)]
 pub struct Language {
@@ -28,7 +31,7 @@ impl Language {
 pub struct Response {
     pub(crate) body: Body,
     pub(crate) status: u16, // status code, not Status struct, as the messages should not be exposed to the user to change
-    pub(crate) cookies: Option<HashMap<String, String>>,
+    pub(crate) cookies: Vec<crate::cookie::Cookie>,
     pub(crate) resource: String,
 
     pub(crate) language: Language,
@@ -54,16 +57,44 @@ impl Body {
         Body {
             content: content.to_string(),
             encoding: encoding.to_string(),
-            other: match other {
-                Some(map) => map,
-                None => HashMap::new(),
-            },
+            other: other.unwrap_or_default(),
+        }
+    }
+
+    /// Create a body holding the given bytes, which are sent as base64 with a `content-transfer` field of
+    /// `"base64"`, using the `identity` encoding
+    pub fn from_bytes(bytes: &[u8]) -> Body {
+        let mut other = HashMap::new();
+
+        other.insert(CONTENT_TRANSFER.to_string(), Value::String("base64".to_string()));
+
+        Body::new(base64(bytes), "identity", Some(other))
+    }
+
+    /// The raw bytes of the content: decoded from base64 if the body was made with [`Body::from_bytes`], otherwise
+    /// the UTF-8 of the content
+    pub fn bytes(&self) -> Result<Vec<u8>, String> {
+        match self.other.get(CONTENT_TRANSFER) {
+            None => Ok(self.content.as_bytes().to_vec()),
+            Some(Value::String(transfer)) if transfer == "base64" => {
+                unbase64(&self.content).ok_or_else(|| "Body is not valid base64".to_string())
+            }
+            Some(transfer) => Err(format!("Content transfer {} is not supported", transfer)),
         }
     }
+
+    /// Set the content to the given text, dropping any `content-transfer` marker
+    pub(crate) fn set_text(&mut self, content: String) {
+        self.content = content;
+        self.other.remove(CONTENT_TRANSFER);
+    }
 }
 
+/// the body field marking how binary content is carried in the `content` string
+const CONTENT_TRANSFER: &str = "content-transfer";
+
 /// The jsontp request, containing the jsontp version, specified by the standard
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct JsontpRequest {
     pub(crate) jsontp: String,
     #[serde(rename = "type")]
@@ -72,6 +103,17 @@ pub struct JsontpRequest {
     pub(crate) resource: String,
     pub headers: HashMap<String, Value>,
     pub body: Body,
+    /// set by [`crate::session::SessionMiddleware`], never sent over the wire
+    #[serde(skip)]
+    pub(crate) session: Option<crate::session::Session>,
+    /// set by the server from the connection the request arrived on, never sent over the wire
+    #[serde(skip)]
+    pub(crate) peer: Option<crate::transport::Address>,
+    /// the parameters matched by the route, in order
+    #[serde(skip)]
+    pub(crate) params: Vec<(String, String)>,
+    #[serde(skip)]
+    pub(crate) state: Option<std::sync::Arc<crate::extract::StateMap>>,
 }
 
 /// The status of a jsontp response, containing the code, formal message and human message
@@ -100,11 +142,29 @@ pub struct JsontpResponse {
     pub resource: String,
     pub headers: HashMap<String, Value>,
     pub body: Body,
+    /// the body as it was sent, if the client decoded it, never sent over the wire
+    #[serde(skip)]
+    pub(crate) encoded_body: Option<Body>,
 }
 
 impl JsontpRequest {
+    /// The resource the request is for
+    pub fn resource(&self) -> &str {
+        &self.resource
+    }
+
+    /// Change the resource the request is for, e.g. to rewrite it before it is routed
+    pub fn set_resource<T: ToString>(&mut self, resource: T) {
+        self.resource = resource.to_string();
+    }
+
+    /// The address the request came from, if it arrived over a connection
+    pub fn peer(&self) -> Option<&crate::transport::Address> {
+        self.peer.as_ref()
+    }
+
     pub(crate) fn validate(&self) -> Result<(), String> {
-        for field in vec![
+        for field in [
             self.jsontp.clone(),
             self.type_of_request.clone(),
             self.method.clone(),
@@ -117,9 +177,8 @@ impl JsontpRequest {
             }
         }
 
-        let allowed_methods = vec!["GET", "POST", "PUT", "DELETE"];
-
-        if !allowed_methods.contains(&self.method.as_str()) {
+        // which methods a route accepts is up to the server, see `Server::route_methods`
+        if !crate::method::Method::is_valid(&self.method) {
             return Err(format!("Method {} is not allowed", self.method));
         }
 
@@ -127,11 +186,7 @@ impl JsontpRequest {
             return Err(format!("Type {} is not allowed", self.type_of_request));
         }
 
-        let allowed_encodings = vec!["gzip", "deflate", "br", "identity"];
-
-        if !allowed_encodings.contains(&self.body.encoding.as_str()) {
-            return Err(format!("Encoding {} is not allowed", self.body.encoding));
-        }
+        // as with methods, which encodings are allowed is up to the server, see `Server::encodings`
 
         Ok(())
     }
@@ -197,25 +252,88 @@ impl JsontpRequest {
             Ok(_) => Response::new_manual(
                 body,
                 status,
-                cookies.map(|map| {
-                    map.into_iter()
-                        .map(|(k, v)| (k.to_string(), v.to_string()))
-                        .collect()
-                }),
+                cookies,
                 self.resource.clone(),
                 language,
                 headers,
             ),
-            Err(e) => {
-                return Response::new_manual(
-                    Body::new(e, "identity", None),
-                    400,
-                    None,
-                    self.resource.clone(),
-                    language,
-                    None,
-                );
+            Err(e) => Response::new_manual(
+                Body::new(e, "identity", None),
+                400,
+                None,
+                self.resource.clone(),
+                language,
+                None,
+            ),
+        }
+    }
+}
+
+/// lowercase hex encoding of the given bytes
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// standard base64 encoding of the given bytes, with padding
+pub(crate) fn base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
             }
         }
     }
+
+    out
+}
+
+/// decodes padded standard base64, returning `None` if it is malformed
+pub(crate) fn unbase64(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(4) {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+
+    for (index, chunk) in s.as_bytes().chunks(4).enumerate() {
+        let last = index == s.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+
+        let mut n = 0u32;
+
+        for &c in &chunk[..4 - padding] {
+            let value = BASE64_ALPHABET.iter().position(|&a| a == c)? as u32;
+            n = n << 6 | value;
+        }
+
+        n <<= 6 * padding as u32;
+
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+
+    Some(out)
+}
+
+/// decodes lowercase or uppercase hex, returning `None` if it is malformed
+pub(crate) fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
 }
@@ -0,0 +1,199 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::canonical::to_canonical_vec;
+use crate::middleware::Middleware;
+use crate::shared::{hex, unhex, Body, JsontpRequest, JsontpResponse, Language, Response, DATE_FORMAT};
+
+const ALGORITHM: &str = "hmac-sha256";
+
+/// A set of named HMAC keys, used to sign messages and to check their signatures
+///
+/// signed messages carry their signature in the `signature` header, next to the `date` it was made at:
+///
+/// ```json
+/// "signature": { "key-id": "2024-01", "algorithm": "hmac-sha256", "value": "9f86d0..." }
+/// ```
+///
+/// the signature covers the [canonical form](crate::canonical::to_canonical_string) of the whole message but that
+/// header. the first key added signs; the others are only accepted when checking, so keys can be rotated by adding
+/// the new key first and removing the old one once nothing signs with it anymore
+#[derive(Clone)]
+pub struct Keyring {
+    keys: Vec<(String, Vec<u8>)>,
+    max_skew: Duration,
+}
+
+impl Default for Keyring {
+    fn default() -> Self {
+        Keyring::new()
+    }
+}
+
+impl Keyring {
+    /// Create a new keyring with no keys, accepting messages dated up to five minutes away from now
+    pub fn new() -> Keyring {
+        Keyring {
+            keys: Vec::new(),
+            max_skew: Duration::from_secs(300),
+        }
+    }
+
+    /// Add a key with the given id
+    pub fn key<T: ToString>(mut self, id: T, secret: &[u8]) -> Keyring {
+        self.keys.push((id.to_string(), secret.to_vec()));
+        self
+    }
+
+    /// Set how far the `date` of a message may be from the current time for its signature to be accepted
+    pub fn max_skew(mut self, max_skew: Duration) -> Keyring {
+        self.max_skew = max_skew;
+        self
+    }
+
+    /// Sign the request, setting its `date` header to now if it has none
+    pub fn sign_request(&self, request: &mut JsontpRequest) -> Result<(), String> {
+        request
+            .headers
+            .entry("date".to_string())
+            .or_insert_with(|| Value::String(Utc::now().format(DATE_FORMAT).to_string()));
+
+        let signature = self.sign(request)?;
+
+        request.headers.insert("signature".to_string(), signature);
+
+        Ok(())
+    }
+
+    /// Sign the response, whose `date` header is set when it is built
+    pub fn sign_response(&self, response: &mut JsontpResponse) -> Result<(), String> {
+        let signature = self.sign(response)?;
+
+        response.headers.insert("signature".to_string(), signature);
+
+        Ok(())
+    }
+
+    /// Check the signature and date of the request
+    pub fn verify_request(&self, request: &JsontpRequest) -> Result<(), String> {
+        self.verify(request)
+    }
+
+    /// Check the signature and date of the response
+    ///
+    /// if the client decoded the body, the body as it was sent is checked instead
+    pub fn verify_response(&self, response: &JsontpResponse) -> Result<(), String> {
+        let encoded_body = match &response.encoded_body {
+            Some(body) => body,
+            None => return self.verify(response),
+        };
+
+        let mut message = serde_json::to_value(response).map_err(|e| format!("Error serializing message: {}", e))?;
+        message["body"] = serde_json::to_value(encoded_body).map_err(|e| format!("Error serializing message: {}", e))?;
+
+        self.verify(&message)
+    }
+
+    fn sign<M: Serialize>(&self, message: &M) -> Result<Value, String> {
+        let (id, secret) = self.keys.first().ok_or("No key to sign with")?;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+        mac.update(&signed_bytes(message));
+
+        let mut signature = serde_json::Map::new();
+        signature.insert("key-id".to_string(), Value::String(id.clone()));
+        signature.insert("algorithm".to_string(), Value::String(ALGORITHM.to_string()));
+        signature.insert("value".to_string(), Value::String(hex(&mac.finalize().into_bytes())));
+
+        Ok(Value::Object(signature))
+    }
+
+    fn verify<M: Serialize>(&self, message: &M) -> Result<(), String> {
+        let message = serde_json::to_value(message).map_err(|e| format!("Error serializing message: {}", e))?;
+        let headers = &message["headers"];
+
+        let signature = &headers["signature"];
+
+        if signature.is_null() {
+            return Err("Message is not signed".to_string());
+        }
+
+        if signature["algorithm"] != ALGORITHM {
+            return Err(format!("Unsupported signature algorithm {}", signature["algorithm"]));
+        }
+
+        let id = signature["key-id"].as_str().ok_or("Signature has no key id")?;
+
+        let (_, secret) = self
+            .keys
+            .iter()
+            .find(|(key_id, _)| key_id == id)
+            .ok_or_else(|| format!("Unknown signing key {}", id))?;
+
+        let value = signature["value"]
+            .as_str()
+            .and_then(unhex)
+            .ok_or("Malformed signature")?;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+        mac.update(&signed_bytes(&message));
+        mac.verify_slice(&value).map_err(|_| "Invalid signature".to_string())?;
+
+        let date = headers["date"].as_str().ok_or("Signed message has no date")?;
+
+        let date = DateTime::parse_from_str(date, DATE_FORMAT)
+            .or_else(|_| DateTime::parse_from_rfc3339(date))
+            .map_err(|e| format!("Invalid date {}: {}", date, e))?;
+
+        let skew = (Utc::now() - date.with_timezone(&Utc)).abs();
+
+        if skew.to_std().unwrap_or(Duration::MAX) > self.max_skew {
+            return Err(format!("Message date {} is too far from now", date));
+        }
+
+        Ok(())
+    }
+}
+
+/// the bytes a signature is computed over: the message without its `signature` header, in canonical form
+fn signed_bytes<M: Serialize>(message: &M) -> Vec<u8> {
+    let mut message = serde_json::to_value(message).unwrap();
+
+    if let Some(Value::Object(headers)) = message.get_mut("headers") {
+        headers.remove("signature");
+    }
+
+    to_canonical_vec(&message).unwrap()
+}
+
+/// Middleware which rejects requests that are not signed by a key in its keyring, answering them with a 401
+pub struct SignatureMiddleware {
+    keyring: Keyring,
+}
+
+impl SignatureMiddleware {
+    /// Check requests against the given keyring
+    pub fn new(keyring: Keyring) -> SignatureMiddleware {
+        SignatureMiddleware { keyring }
+    }
+}
+
+impl Middleware for SignatureMiddleware {
+    fn before(&self, request: &mut JsontpRequest) -> Option<Response> {
+        let error = self.keyring.verify_request(request).err()?;
+
+        Some(Response::new_manual(
+            Body::new(error, "identity", None),
+            401,
+            None,
+            request.resource.clone(),
+            Language::default(),
+            None,
+        ))
+    }
+}
@@ -0,0 +1,55 @@
+use crate::client_imp::Request;
+use crate::server_imp::Server;
+use crate::shared::JsontpResponse;
+use crate::transport::{new_memory_address, Address};
+
+/// A client which hands requests straight to a [`Server`], without starting it or opening any connections
+///
+/// requests go through the same routing and response building as they would over the network, so handlers can be
+/// unit tested quickly and deterministically. the client prepares requests and handles responses as
+/// [`Request::send`] does, e.g. signing requests and keeping cookies in the jar, and every request comes from the
+/// same in-memory peer address
+pub struct TestClient<'a> {
+    server: &'a Server,
+    peer: Address,
+}
+
+impl<'a> TestClient<'a> {
+    /// Create a new test client for the given server
+    pub fn new(server: &'a Server) -> TestClient<'a> {
+        TestClient {
+            server,
+            peer: new_memory_address(),
+        }
+    }
+
+    /// The address requests from this client come from
+    pub fn peer(&self) -> &Address {
+        &self.peer
+    }
+
+    /// Send the request to the server, returning its response
+    ///
+    /// panics if the request cannot be sent, or its response cannot be handled, as [`TestClient::try_send`] reports
+    pub fn send(&self, request: Request) -> JsontpResponse {
+        match self.try_send(request) {
+            Ok(response) => response,
+            Err(e) => panic!("failed to send request: {}", e),
+        }
+    }
+
+    /// Send the request to the server, returning its response, or why the client could not send it or handle the
+    /// response, e.g. because its body could not be serialized
+    pub fn try_send(&self, mut request: Request) -> Result<JsontpResponse, String> {
+        request.prepare(false)?;
+
+        let mut inner = request.inner.clone();
+        inner.peer = Some(self.peer.clone());
+
+        let mut response = self.server.dispatch(inner);
+
+        request.receive(&mut response)?;
+
+        Ok(response)
+    }
+}
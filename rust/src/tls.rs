@@ -0,0 +1,145 @@
+use std::sync::Arc;
+
+use rustls::pki_types::ServerName;
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, ServerConfig, ServerConnection, StreamOwned};
+
+pub use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+pub use rustls::RootCertStore;
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// The TLS configuration of a server, containing its certificate chain and private key
+#[derive(Clone)]
+pub struct ServerTlsConfig {
+    pub(crate) inner: Arc<ServerConfig>,
+}
+
+impl ServerTlsConfig {
+    /// Create a new TLS configuration from a DER certificate chain (leaf first) and its private key
+    pub fn new(cert_chain: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Result<ServerTlsConfig, String> {
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format!("Error building TLS config: {}", e))?
+            .with_no_client_auth()
+            .with_single_cert(cert_chain, key)
+            .map_err(|e| format!("Error loading certificate: {}", e))?;
+
+        Ok(ServerTlsConfig {
+            inner: Arc::new(config),
+        })
+    }
+
+    /// Create a new TLS configuration which also requires clients to present a certificate signed by one of `client_roots`
+    pub fn with_client_auth(
+        cert_chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        client_roots: RootCertStore,
+    ) -> Result<ServerTlsConfig, String> {
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(client_roots), provider())
+            .build()
+            .map_err(|e| format!("Error building client verifier: {}", e))?;
+
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format!("Error building TLS config: {}", e))?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(cert_chain, key)
+            .map_err(|e| format!("Error loading certificate: {}", e))?;
+
+        Ok(ServerTlsConfig {
+            inner: Arc::new(config),
+        })
+    }
+
+    /// Use an existing `rustls` server configuration as-is
+    pub fn from_rustls(config: Arc<ServerConfig>) -> ServerTlsConfig {
+        ServerTlsConfig { inner: config }
+    }
+
+    pub(crate) fn accept<S: std::io::Read + std::io::Write>(&self, stream: S) -> Result<StreamOwned<ServerConnection, S>, String> {
+        let connection = ServerConnection::new(self.inner.clone())
+            .map_err(|e| format!("Error starting TLS session: {}", e))?;
+
+        Ok(StreamOwned::new(connection, stream))
+    }
+}
+
+/// The TLS configuration of a client, containing the trusted roots and an optional client certificate
+pub struct ClientTlsConfig {
+    pub(crate) roots: RootCertStore,
+    pub(crate) identity: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+}
+
+impl Clone for ClientTlsConfig {
+    fn clone(&self) -> Self {
+        ClientTlsConfig {
+            roots: self.roots.clone(),
+            identity: self
+                .identity
+                .as_ref()
+                .map(|(cert_chain, key)| (cert_chain.clone(), key.clone_key())),
+        }
+    }
+}
+
+impl Default for ClientTlsConfig {
+    fn default() -> Self {
+        ClientTlsConfig::new()
+    }
+}
+
+impl ClientTlsConfig {
+    /// Create a new client configuration, trusting no roots
+    pub fn new() -> ClientTlsConfig {
+        ClientTlsConfig {
+            roots: RootCertStore::empty(),
+            identity: None,
+        }
+    }
+
+    /// Trust the given DER root certificate
+    pub fn root_certificate(mut self, cert: CertificateDer<'static>) -> Result<ClientTlsConfig, String> {
+        self.roots
+            .add(cert)
+            .map_err(|e| format!("Error adding root certificate: {}", e))?;
+
+        Ok(self)
+    }
+
+    /// Replace the trusted roots with the given store
+    pub fn root_store(mut self, roots: RootCertStore) -> ClientTlsConfig {
+        self.roots = roots;
+        self
+    }
+
+    /// Present the given certificate chain and private key to servers that ask for one
+    pub fn client_certificate(mut self, cert_chain: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> ClientTlsConfig {
+        self.identity = Some((cert_chain, key));
+        self
+    }
+
+    pub(crate) fn connect<S: std::io::Read + std::io::Write>(&self, host: &str, stream: S) -> Result<StreamOwned<ClientConnection, S>, String> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format!("Error building TLS config: {}", e))?
+            .with_root_certificates(self.roots.clone());
+
+        let config = match &self.identity {
+            Some((cert_chain, key)) => builder
+                .with_client_auth_cert(cert_chain.clone(), key.clone_key())
+                .map_err(|e| format!("Error loading client certificate: {}", e))?,
+            None => builder.with_no_client_auth(),
+        };
+
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|e| format!("Invalid server name {}: {}", host, e))?;
+
+        let connection = ClientConnection::new(Arc::new(config), server_name)
+            .map_err(|e| format!("Error starting TLS session: {}", e))?;
+
+        Ok(StreamOwned::new(connection, stream))
+    }
+}
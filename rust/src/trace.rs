@@ -0,0 +1,11 @@
+/// emits a warning through `tracing` when the `tracing` feature is enabled, and does nothing otherwise
+///
+/// only takes a format string and its arguments, so that the arguments are still used without the feature
+macro_rules! trace_warn {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)*);
+        #[cfg(not(feature = "tracing"))]
+        let _ = format_args!($($arg)*);
+    }};
+}
@@ -0,0 +1,565 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+/// The address of either end of a jsontp connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    /// a TCP socket address
+    Tcp(SocketAddr),
+    /// a unix domain socket, with its path if it has one (client sockets are usually unnamed)
+    #[cfg(unix)]
+    Unix(Option<PathBuf>),
+    /// an in-memory transport or pipe end, numbered in the order they were created
+    Memory(usize),
+}
+
+impl Address {
+    /// The TCP socket address, if this is a TCP address
+    pub fn tcp(&self) -> Option<SocketAddr> {
+        match self {
+            Address::Tcp(addr) => Some(*addr),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for Address {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Address::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            #[cfg(unix)]
+            Address::Unix(None) => write!(f, "unix:(unnamed)"),
+            Address::Memory(id) => write!(f, "memory:{}", id),
+        }
+    }
+}
+
+/// A connection that whole jsontp messages are exchanged over
+///
+/// the server reads one request from a connection and writes one response back; the client does the opposite
+pub trait Connection: Send {
+    /// Read the next message, returning its raw bytes
+    fn read_message(&mut self) -> std::io::Result<Vec<u8>>;
+
+    /// Write a whole message
+    fn write_message(&mut self, message: &[u8]) -> std::io::Result<()>;
+
+    /// The address of the other end of the connection
+    fn peer(&self) -> Address;
+}
+
+impl<C: Connection + ?Sized> Connection for Box<C> {
+    fn read_message(&mut self) -> std::io::Result<Vec<u8>> {
+        (**self).read_message()
+    }
+
+    fn write_message(&mut self, message: &[u8]) -> std::io::Result<()> {
+        (**self).write_message(message)
+    }
+
+    fn peer(&self) -> Address {
+        (**self).peer()
+    }
+}
+
+/// A byte stream that jsontp messages can be sent over once [`Framed`], such as a TCP or unix socket
+pub trait Stream: Read + Write + Send {
+    /// The address of the other end of the stream
+    fn peer(&self) -> Address;
+
+    /// Make reads fail once they have waited this long for data, or wait forever if `None`
+    ///
+    /// streams which cannot time out ignore this
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: Stream + ?Sized> Stream for Box<S> {
+    fn peer(&self) -> Address {
+        (**self).peer()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+impl Stream for TcpStream {
+    fn peer(&self) -> Address {
+        match self.peer_addr() {
+            Ok(addr) => Address::Tcp(addr),
+            Err(_) => Address::Tcp(SocketAddr::from(([0, 0, 0, 0], 0))),
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn peer(&self) -> Address {
+        Address::Unix(
+            self.peer_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(Path::to_path_buf)),
+        )
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<S: Stream> Stream for rustls::StreamOwned<rustls::ServerConnection, S> {
+    fn peer(&self) -> Address {
+        self.sock.peer()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<S: Stream> Stream for rustls::StreamOwned<rustls::ClientConnection, S> {
+    fn peer(&self) -> Address {
+        self.sock.peer()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+/// A [`Connection`] over a byte stream, where each message is a single JSON object
+///
+/// messages are delimited by the JSON syntax itself, so this is compatible with peers that just write out the
+/// serialized request or response, as the other jsontp libraries do
+///
+/// messages larger than [`DEFAULT_MAX_MESSAGE_SIZE`] are rejected, unless set otherwise with
+/// [`Framed::max_message_size`]
+pub struct Framed<S: Stream> {
+    inner: BufReader<S>,
+    max_message_size: usize,
+    read_timeout: Option<Duration>,
+}
+
+/// The largest message a [`Framed`] connection reads by default, 16 MiB
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+impl<S: Stream> Framed<S> {
+    /// Wrap the given byte stream
+    pub fn new(stream: S) -> Framed<S> {
+        Framed {
+            inner: BufReader::new(stream),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            read_timeout: None,
+        }
+    }
+
+    /// Set the largest message to read, in bytes; reading a larger one fails with
+    /// [`std::io::ErrorKind::InvalidData`]
+    pub fn max_message_size(mut self, max_message_size: usize) -> Framed<S> {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Set how long reading a whole message may take, however the peer spreads it out; taking longer fails with
+    /// [`std::io::ErrorKind::TimedOut`]
+    ///
+    /// this changes the read timeout of the stream while a message is read
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Framed<S> {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Get a reference to the underlying stream
+    pub fn get_ref(&self) -> &S {
+        self.inner.get_ref()
+    }
+
+    /// Get a mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut S {
+        self.inner.get_mut()
+    }
+}
+
+impl<S: Stream> Connection for Framed<S> {
+    fn read_message(&mut self) -> std::io::Result<Vec<u8>> {
+        let mut message = Vec::new();
+        let mut scanner = Scanner::default();
+
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            // each read may only wait for whatever is left of the time for the whole message
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+
+                if remaining.is_zero() {
+                    return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out reading message"));
+                }
+
+                self.inner.get_mut().set_read_timeout(Some(remaining))?;
+            }
+
+            let buffer = self.inner.fill_buf()?;
+
+            if buffer.is_empty() {
+                let error = if message.is_empty() { "connection closed" } else { "connection closed mid-message" };
+
+                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, error));
+            }
+
+            let (used, complete) = scanner.scan(buffer)?;
+
+            if message.len() + used > self.max_message_size {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("message is larger than {} bytes", self.max_message_size),
+                ));
+            }
+
+            message.extend_from_slice(&buffer[..used]);
+            self.inner.consume(used);
+
+            if complete {
+                // drop any whitespace that came before the message
+                let start = message.iter().position(|&byte| byte == b'{').unwrap_or(0);
+
+                return Ok(message.split_off(start));
+            }
+        }
+    }
+
+    fn write_message(&mut self, message: &[u8]) -> std::io::Result<()> {
+        let stream = self.inner.get_mut();
+
+        stream.write_all(message)?;
+        stream.flush()
+    }
+
+    fn peer(&self) -> Address {
+        self.inner.get_ref().peer()
+    }
+}
+
+/// finds where a JSON object ends, without parsing the values inside it
+#[derive(Default)]
+struct Scanner {
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl Scanner {
+    /// scans `bytes`, returning how many belong to the message and whether the message is complete
+    fn scan(&mut self, bytes: &[u8]) -> std::io::Result<(usize, bool)> {
+        for (i, &byte) in bytes.iter().enumerate() {
+            if self.depth == 0 {
+                match byte {
+                    b'{' => self.depth = 1,
+                    b' ' | b'\t' | b'\r' | b'\n' => {}
+                    _ => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "message is not a JSON object",
+                        ))
+                    }
+                }
+
+                continue;
+            }
+
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+
+                continue;
+            }
+
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' => {
+                    self.depth -= 1;
+
+                    if self.depth == 0 {
+                        return Ok((i + 1, true));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok((bytes.len(), false))
+    }
+}
+
+/// A source of incoming connections for the server, such as a bound TCP or unix socket
+///
+/// transports are polled, so that the accept loop can notice a shutdown request: `accept` returns an error of
+/// kind [`std::io::ErrorKind::WouldBlock`] when there is no pending connection
+pub trait Transport: Send + Sync {
+    /// Accept a pending connection
+    fn accept(&self) -> std::io::Result<Box<dyn Stream>>;
+
+    /// The address the transport is listening on
+    fn local_addr(&self) -> std::io::Result<Address>;
+}
+
+impl Transport for TcpListener {
+    fn accept(&self) -> std::io::Result<Box<dyn Stream>> {
+        let (stream, _) = TcpListener::accept(self)?;
+
+        stream.set_nonblocking(false)?;
+
+        Ok(Box::new(stream))
+    }
+
+    fn local_addr(&self) -> std::io::Result<Address> {
+        TcpListener::local_addr(self).map(Address::Tcp)
+    }
+}
+
+/// Bind a TCP transport to the given address, e.g. `"127.0.0.1:0"`
+pub fn bind_tcp<A: std::net::ToSocketAddrs>(address: A) -> std::io::Result<TcpListener> {
+    let listener = TcpListener::bind(address)?;
+
+    listener.set_nonblocking(true)?;
+
+    Ok(listener)
+}
+
+/// A unix domain socket transport, which removes its socket file once it is dropped so that the path can be bound again
+#[cfg(unix)]
+pub struct UnixTransport {
+    inner: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+    /// Bind a unix domain socket at the given path, which must not exist yet
+    pub fn bind<P: AsRef<Path>>(path: P) -> std::io::Result<UnixTransport> {
+        let inner = UnixListener::bind(&path)?;
+
+        inner.set_nonblocking(true)?;
+
+        Ok(UnixTransport {
+            inner,
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixTransport {
+    fn accept(&self) -> std::io::Result<Box<dyn Stream>> {
+        let (stream, _) = self.inner.accept()?;
+
+        stream.set_nonblocking(false)?;
+
+        Ok(Box::new(stream))
+    }
+
+    fn local_addr(&self) -> std::io::Result<Address> {
+        Ok(Address::Unix(Some(self.path.clone())))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixTransport {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+static NEXT_MEMORY_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+
+/// a new in-memory address, different from every other one
+pub(crate) fn new_memory_address() -> Address {
+    Address::Memory(NEXT_MEMORY_ADDRESS.fetch_add(1, Ordering::SeqCst))
+}
+
+/// one direction of an in-memory pipe, closed once either end is dropped
+#[derive(Default)]
+struct Buffer {
+    state: Mutex<(VecDeque<u8>, bool)>,
+    ready: Condvar,
+}
+
+/// One end of an in-memory byte pipe, made with [`pipe`]
+pub struct MemoryStream {
+    incoming: Arc<Buffer>,
+    outgoing: Arc<Buffer>,
+    peer: usize,
+    read_timeout: Option<Duration>,
+}
+
+/// Create a connected pair of in-memory streams; whatever is written to one can be read from the other
+pub fn pipe() -> (MemoryStream, MemoryStream) {
+    let a = Arc::new(Buffer::default());
+    let b = Arc::new(Buffer::default());
+
+    let first = NEXT_MEMORY_ADDRESS.fetch_add(2, Ordering::SeqCst);
+
+    (
+        MemoryStream {
+            incoming: a.clone(),
+            outgoing: b.clone(),
+            peer: first + 1,
+            read_timeout: None,
+        },
+        MemoryStream {
+            incoming: b,
+            outgoing: a,
+            peer: first,
+            read_timeout: None,
+        },
+    )
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut state = self.incoming.state.lock().unwrap();
+
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+
+        // wait for data, or for the other end to be dropped
+        while state.0.is_empty() && !state.1 {
+            state = match deadline {
+                None => self.incoming.ready.wait(state).unwrap(),
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+
+                    if remaining.is_zero() {
+                        return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out waiting for data"));
+                    }
+
+                    self.incoming.ready.wait_timeout(state, remaining).unwrap().0
+                }
+            };
+        }
+
+        let n = buf.len().min(state.0.len());
+
+        for (slot, byte) in buf.iter_mut().zip(state.0.drain(..n)) {
+            *slot = byte;
+        }
+
+        Ok(n)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut state = self.outgoing.state.lock().unwrap();
+
+        if state.1 {
+            return Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "other end of the pipe was dropped"));
+        }
+
+        state.0.extend(buf);
+        self.outgoing.ready.notify_all();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        for buffer in [&self.incoming, &self.outgoing] {
+            buffer.state.lock().unwrap().1 = true;
+            buffer.ready.notify_all();
+        }
+    }
+}
+
+impl Stream for MemoryStream {
+    fn peer(&self) -> Address {
+        Address::Memory(self.peer)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+}
+
+/// An in-memory transport, which serves connections opened through its [`MemoryConnector`]
+pub struct MemoryTransport {
+    pending: Mutex<Receiver<MemoryStream>>,
+    id: usize,
+}
+
+/// Opens connections to a [`MemoryTransport`]
+#[derive(Clone)]
+pub struct MemoryConnector {
+    sender: Sender<MemoryStream>,
+}
+
+impl MemoryTransport {
+    /// Create a new in-memory transport, and a connector to reach it with
+    pub fn new() -> (MemoryTransport, MemoryConnector) {
+        let (sender, receiver) = mpsc::channel();
+
+        (
+            MemoryTransport {
+                pending: Mutex::new(receiver),
+                id: NEXT_MEMORY_ADDRESS.fetch_add(1, Ordering::SeqCst),
+            },
+            MemoryConnector { sender },
+        )
+    }
+}
+
+impl MemoryConnector {
+    /// Open a new connection to the transport, returning the client end
+    pub fn connect(&self) -> std::io::Result<Framed<MemoryStream>> {
+        let (client, server) = pipe();
+
+        self.sender
+            .send(server)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "transport was dropped"))?;
+
+        Ok(Framed::new(client))
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn accept(&self) -> std::io::Result<Box<dyn Stream>> {
+        match self.pending.lock().unwrap().try_recv() {
+            Ok(stream) => Ok(Box::new(stream)),
+            Err(_) => Err(std::io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Address> {
+        Ok(Address::Memory(self.id))
+    }
+}